@@ -3,7 +3,7 @@
 
 #let result = plugin.ask_number(encode-askNumber(("numberCount": 10)))
 
-#let unpacked = decode-result(result).at(0).at("numbers")
+#let unpacked = decode-result(result, 0).at(0).at("numbers")
 
 = Converter example
 #table(
@@ -29,5 +29,5 @@
 	"Roman",
 	..nums,
 	"Decimal",
-	..nums.map(roman => str(decode-decimalResult(plugin.roman_to_decimal(encode-toDecimal(("roman": roman)))).at(0).at("decimal"))),
+	..nums.map(roman => str(decode-decimalResult(plugin.roman_to_decimal(encode-toDecimal(("roman": roman))), 0).at(0).at("decimal"))),
 )
@@ -13,37 +13,101 @@ void big_endian_encode(int value, uint8_t *buffer, int size) {
     }
 }
 
-size_t list_size(void *list, size_t size, size_t (*sf)(const void*), size_t element_size) {
-    size_t result = 0;
+uint64_t big_endian_decode_u64(uint8_t const *buffer, int size) {
+    uint64_t value = 0;
     for (int i = 0; i < size; i++) {
-        result += sf(list + i * element_size);
+        value = (value << 8) | buffer[i];
     }
-    return result;
+    return value;
 }
 
-size_t int_size(const void* elem) {
-    return TYPST_INT_SIZE;
+void big_endian_encode_u64(uint64_t value, uint8_t *buffer, int size) {
+    for (int i = 0; i < size; i++) {
+        buffer[i] = (value >> (8 * (size - i - 1))) & 0xFF;
+    }
 }
-size_t float_size(const void *elem) {
-    return TYPST_INT_SIZE;
+
+float decode_float(uint8_t *buffer) {
+	int value = big_endian_decode(buffer, TYPST_INT_SIZE);
+	if (value == 0) {
+		return 0.0f;
+	}
+	union FloatBuffer {
+		float f;
+		int i;
+	} float_buffer;
+	float_buffer.i = value;
+	return float_buffer.f;
 }
-size_t bool_size(const void *elem) {
-    return TYPST_INT_SIZE;
+
+void encode_float(float value, uint8_t *buffer) {
+	if (value == 0.0f) {
+		big_endian_encode(0, buffer, TYPST_INT_SIZE);
+	} else {
+		union FloatBuffer {
+			float f;
+			int i;
+		} float_buffer;
+		float_buffer.f = value;
+		big_endian_encode(float_buffer.i, buffer, TYPST_INT_SIZE);
+	}
+}
+
+double decode_double(uint8_t *buffer) {
+	union DoubleBuffer {
+		double d;
+		uint64_t i;
+	} double_buffer;
+	double_buffer.i = big_endian_decode_u64(buffer, 8);
+	return double_buffer.d;
+}
+
+void encode_double(double value, uint8_t *buffer) {
+	union DoubleBuffer {
+		double d;
+		uint64_t i;
+	} double_buffer;
+	double_buffer.d = value;
+	big_endian_encode_u64(double_buffer.i, buffer, 8);
 }
-size_t char_size(const void *elem) {
-    return 1;
+
+int map_find(char *const *keys, size_t len, const char *key) {
+    for (size_t i = 0; i < len; i++) {
+        if (strcmp(keys[i], key) == 0) {
+            return i;
+        }
+    }
+    return -1;
 }
+
 size_t string_size(const void *elem) {
+    if (!elem || !((char *)elem)[0]) {
+        return 1;
+    }
     return strlen((char *)elem) + 1;
 }
 
-void free_Number(Number *s) {
-    if (s->romanRepresentation) {
-        free(s->romanRepresentation);
-    }
+size_t Number_size(const void *value);
+int encode_Number(const Number *s, uint8_t *__input_buffer, size_t *buffer_len, size_t *buffer_offset);
+int init_Number(Number *s) {
+    int err;
+    (void)err;
+    memset(s, 0, sizeof(*s));
+    return 0;
 }
-size_t Number_size(const void *s){
-	return TYPST_INT_SIZE + TYPST_INT_SIZE + strlen(((Number*)s)->romanRepresentation) + 1 + 1 + 1;
+void free_Number(Number *s) {
+    free(s->romanRepresentation);
+}
+size_t Number_size(const void *value) {
+    const Number *s = value;
+    (void)s;
+    size_t size = 0;
+    size += TYPST_INT_SIZE;
+    size += TYPST_INT_SIZE;
+    size += string_size(s->romanRepresentation);
+    size += 1;
+    size += 1;
+    return size;
 }
 int encode_Number(const Number *s, uint8_t *__input_buffer, size_t *buffer_len, size_t *buffer_offset) {
     size_t __buffer_offset = 0;    size_t s_size = Number_size(s);
@@ -61,19 +125,10 @@ int encode_Number(const Number *s, uint8_t *__input_buffer, size_t *buffer_len,
     *buffer_offset += __buffer_offset;
     return 0;
 }
-void free_decimalResult(decimalResult *s) {
-}
-size_t decimalResult_size(const void *s){
-	return TYPST_INT_SIZE;
-}
-int encode_decimalResult(const decimalResult *s) {
-    size_t buffer_len = decimalResult_size(s);
-    INIT_BUFFER_PACK(buffer_len)
+int init_askNumber(askNumber *s) {
     int err;
-	(void)err;
-    INT_PACK(s->decimal)
-
-    wasm_minimal_protocol_send_result_to_host(__input_buffer, buffer_len);
+    (void)err;
+    memset(s, 0, sizeof(*s));
     return 0;
 }
 void free_askNumber(askNumber *s) {
@@ -86,27 +141,27 @@ int decode_askNumber(size_t buffer_len, askNumber *out) {
     FREE_BUFFER()
     return 0;
 }
-void free_toDecimal(toDecimal *s) {
-    if (s->roman) {
-        free(s->roman);
-    }
-}
-int decode_toDecimal(size_t buffer_len, toDecimal *out) {
-    INIT_BUFFER_UNPACK(buffer_len)
+int init_result(result *s) {
     int err;
     (void)err;
-    NEXT_STR(out->roman)
-    FREE_BUFFER()
+    memset(s, 0, sizeof(*s));
     return 0;
 }
 void free_result(result *s) {
-    for (size_t i = 0; i < s->numbers_len; i++) {
-    free_Number(&s->numbers[i]);
+    for (size_t i1 = 0; i1 < s->numbers_len; i1++) {
+        free_Number(&s->numbers[i1]);
     }
     free(s->numbers);
 }
-size_t result_size(const void *s){
-	return TYPST_INT_SIZE + list_size(((result*)s)->numbers, ((result*)s)->numbers_len, Number_size, sizeof(*((result*)s)->numbers));
+size_t result_size(const void *value) {
+    const result *s = value;
+    (void)s;
+    size_t size = 0;
+    size += TYPST_INT_SIZE;
+    for (size_t i1 = 0; i1 < s->numbers_len; i1++) {
+        size += Number_size(&s->numbers[i1]);
+    }
+    return size;
 }
 int encode_result(const result *s) {
     size_t buffer_len = result_size(s);
@@ -114,8 +169,8 @@ int encode_result(const result *s) {
     int err;
 	(void)err;
     INT_PACK(s->numbers_len)
-    for (size_t i = 0; i < s->numbers_len; i++) {
-        if ((err = encode_Number(&s->numbers[i], __input_buffer + __buffer_offset, &buffer_len, &__buffer_offset))) {
+    for (size_t i1 = 0; i1 < s->numbers_len; i1++) {
+        if ((err = encode_Number(&s->numbers[i1], __input_buffer + __buffer_offset, &buffer_len, &__buffer_offset))) {
             return err;
         }
     }
@@ -123,3 +178,45 @@ int encode_result(const result *s) {
     wasm_minimal_protocol_send_result_to_host(__input_buffer, buffer_len);
     return 0;
 }
+int init_toDecimal(toDecimal *s) {
+    int err;
+    (void)err;
+    memset(s, 0, sizeof(*s));
+    return 0;
+}
+void free_toDecimal(toDecimal *s) {
+    free(s->roman);
+}
+int decode_toDecimal(size_t buffer_len, toDecimal *out) {
+    INIT_BUFFER_UNPACK(buffer_len)
+    int err;
+    (void)err;
+    NEXT_STR(out->roman)
+    FREE_BUFFER()
+    return 0;
+}
+int init_decimalResult(decimalResult *s) {
+    int err;
+    (void)err;
+    memset(s, 0, sizeof(*s));
+    return 0;
+}
+void free_decimalResult(decimalResult *s) {
+}
+size_t decimalResult_size(const void *value) {
+    const decimalResult *s = value;
+    (void)s;
+    size_t size = 0;
+    size += TYPST_INT_SIZE;
+    return size;
+}
+int encode_decimalResult(const decimalResult *s) {
+    size_t buffer_len = decimalResult_size(s);
+    INIT_BUFFER_PACK(buffer_len)
+    int err;
+	(void)err;
+    INT_PACK(s->decimal)
+
+    wasm_minimal_protocol_send_result_to_host(__input_buffer, buffer_len);
+    return 0;
+}
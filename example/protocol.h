@@ -5,25 +5,27 @@
 #include <stdint.h>
 #include <string.h>
 #include <stdbool.h>
+#include <math.h>
 #include "emscripten.h"
 
+#ifndef PROTOCOL_FUNCTION
 #define PROTOCOL_FUNCTION __attribute__((import_module("typst_env"))) extern
+#endif
 
 PROTOCOL_FUNCTION void wasm_minimal_protocol_send_result_to_host(const uint8_t *ptr, size_t len);
 PROTOCOL_FUNCTION void wasm_minimal_protocol_write_args_to_buffer(uint8_t *ptr);
 
-union FloatBuffer {
-    float f;
-    int i;
-};
-
-void big_endian_encode(int value, uint8_t *buffer, int size);
+typedef struct bytes_t {
+    uint8_t *data;
+    size_t len;
+} bytes;
 
-int big_endian_decode(uint8_t const *buffer, int size);
+// Index of key in the keys of a map or -1 if the key is not in the map
+int map_find(char *const *keys, size_t len, const char *key);
 
 #define TYPST_INT_SIZE 4
 
-#define INIT_BUFFER_UNPACK(buffer_len)                                                             \
+#define INIT_BUFFER_UNPACK(buffer_len)                                                           \
     size_t __buffer_offset = 0;                                                                    \
     uint8_t *__input_buffer = malloc((buffer_len));                                                \
     if (!__input_buffer) {                                                                         \
@@ -31,13 +33,13 @@ int big_endian_decode(uint8_t const *buffer, int size);
     }                                                                                              \
     wasm_minimal_protocol_write_args_to_buffer(__input_buffer);
 
-#define CHECK_BUFFER()                                                                             \
+#define CHECK_BUFFER()                                                                           \
 	if (__buffer_offset >= buffer_len) {                                                           \
 		return 2;                                                                                  \
 	}
 
-#define NEXT_STR(dst)                                                                              \
-	CHECK_BUFFER()                                                                                 \
+#define NEXT_STR(dst)                                                                            \
+	CHECK_BUFFER()                                                                               \
     {                                                                                              \
 		if (__input_buffer[__buffer_offset] == '\0') {                                            \
 			(dst) = malloc(1);                                                                     \
@@ -57,53 +59,103 @@ int big_endian_decode(uint8_t const *buffer, int size);
 		}                                                                                          \
     }
 
-#define NEXT_INT(dst)                                                                              \
-	CHECK_BUFFER()                                                                                 \
-    (dst) = big_endian_decode(__input_buffer + __buffer_offset, TYPST_INT_SIZE);                   \
+#define NEXT_INT(dst)                                                                            \
+	CHECK_BUFFER()                                                                               \
+    (dst) = big_endian_decode(__input_buffer + __buffer_offset, TYPST_INT_SIZE);                \
     __buffer_offset += TYPST_INT_SIZE;
 
-#define NEXT_CHAR(dst)                                                                             \
-	CHECK_BUFFER()                                                                                 \
+#define NEXT_DOUBLE(dst)                                                                         \
+	CHECK_BUFFER()                                                                               \
+    (dst) = decode_double(__input_buffer + __buffer_offset);                                      \
+	__buffer_offset += 8;
+
+#define NEXT_SIZED_INT(dst, size)                                                                \
+	CHECK_BUFFER()                                                                               \
+    (dst) = big_endian_decode_u64(__input_buffer + __buffer_offset, (size));                      \
+    __buffer_offset += (size);
+
+#define NEXT_BYTES(dst, len)                                                                     \
+	NEXT_INT(len)                                                                                \
+	if (__buffer_offset + (len) > buffer_len) {                                                    \
+		return 2;                                                                                  \
+	}                                                                                              \
+	if ((len) == 0) {                                                                              \
+		(dst) = NULL;                                                                              \
+	} else {                                                                                       \
+		(dst) = malloc((len));                                                                     \
+		if (!(dst)) {                                                                              \
+			return 1;                                                                              \
+		}                                                                                          \
+		memcpy((dst), __input_buffer + __buffer_offset, (len));                                    \
+		__buffer_offset += (len);                                                                  \
+	}
+
+#define NEXT_CHAR(dst)                                                                           \
+	CHECK_BUFFER()                                                                               \
     (dst) = __input_buffer[__buffer_offset++];
 
-#define NEXT_FLOAT(dst)                                                                            \
-	CHECK_BUFFER()                                                                                 \
-    {                                                                                              \
-        int __encoded_value;                                                                       \
-        NEXT_INT(__encoded_value);                                                                 \
-        union FloatBuffer __float_buffer;                                                          \
-        __float_buffer.i = __encoded_value;                                                        \
-        (dst) = __float_buffer.f;                                                                  \
-    }
+#define NEXT_ENUM(dst, count)                                                                    \
+	CHECK_BUFFER()                                                                               \
+	if (__input_buffer[__buffer_offset] >= (count)) {                                              \
+		return 3;                                                                                  \
+	}                                                                                              \
+    (dst) = __input_buffer[__buffer_offset++];
+
+#define NEXT_FLOAT(dst)                                                                          \
+	CHECK_BUFFER()                                                                               \
+    (dst) = decode_float(__input_buffer + __buffer_offset);                                       \
+	__buffer_offset += TYPST_INT_SIZE;
     
-#define FREE_BUFFER()                                                                              \
+#define FREE_BUFFER()                                                                            \
     free(__input_buffer);                                                                          \
     __input_buffer = NULL;
 
-#define INIT_BUFFER_PACK(buffer_len)                                                               \
+#define INIT_BUFFER_PACK(buffer_len)                                                             \
     size_t __buffer_offset = 0;                                                                    \
     uint8_t *__input_buffer = malloc((buffer_len));                                                \
     if (!__input_buffer) {                                                                         \
         return 1;                                                                                  \
     }
 
-#define FLOAT_PACK(fp)                                                                             \
+#define FLOAT_PACK(fp)                                                                           \
     {                                                                                              \
-        union FloatBuffer __float_buffer;                                                          \
-        __float_buffer.f = (fp);                                                                    \
-        big_endian_encode(__float_buffer.i, __input_buffer + __buffer_offset, TYPST_INT_SIZE);     \
-        __buffer_offset += TYPST_INT_SIZE;                                                         \
-    }
+		if (fp == 0.0f) {  																	       \
+			big_endian_encode(0, __input_buffer + __buffer_offset, TYPST_INT_SIZE);             \
+		} else {                                                                                   \
+			union FloatBuffer { 																   \
+				float f;   																	       \
+				int i;   																	       \
+			} __float_buffer;                                                                      \
+			__float_buffer.f = (fp);                                                               \
+			big_endian_encode(__float_buffer.i, __input_buffer + __buffer_offset, TYPST_INT_SIZE); \
+		}                                                                                          \
+		__buffer_offset += TYPST_INT_SIZE;                                                       \
+	}
 
-#define INT_PACK(i)                                                                                \
-    big_endian_encode((i), __input_buffer + __buffer_offset, TYPST_INT_SIZE);                      \
+#define INT_PACK(i)                                                                              \
+    big_endian_encode((i), __input_buffer + __buffer_offset, TYPST_INT_SIZE);                   \
     __buffer_offset += TYPST_INT_SIZE;
 
-#define CHAR_PACK(c)                                                                               \
+#define DOUBLE_PACK(d)                                                                           \
+    encode_double((d), __input_buffer + __buffer_offset);                                         \
+    __buffer_offset += 8;
+
+#define SIZED_INT_PACK(i, size)                                                                  \
+    big_endian_encode_u64((uint64_t)(i), __input_buffer + __buffer_offset, (size));              \
+    __buffer_offset += (size);
+
+#define BYTES_PACK(b, len)                                                                       \
+    INT_PACK(len)                                                                                \
+    if ((len) > 0) {                                                                               \
+        memcpy(__input_buffer + __buffer_offset, (b), (len));                                      \
+        __buffer_offset += (len);                                                                  \
+    }
+
+#define CHAR_PACK(c)                                                                             \
     __input_buffer[__buffer_offset++] = (c);
 
-#define STR_PACK(s)                                                                                \
-    if (s == NULL || s[0] == '\0') {                                                               \
+#define STR_PACK(s)                                                                              \
+    if (s == NULL || s[0] == '\0') {                                                              \
         __input_buffer[__buffer_offset++] = '\0';                                                 \
     } else {                                                                                       \
         strcpy((char *)__input_buffer + __buffer_offset, (s));                                     \
@@ -111,38 +163,143 @@ int big_endian_decode(uint8_t const *buffer, int size);
         __input_buffer[__buffer_offset + __str_len] = '\0';                                       \
         __buffer_offset += __str_len + 1;                                                          \
     }
+// Typst length, `abs` is in points and `em` is relative to the font size
+typedef struct {
+    float abs;
+    float em;
+} length;
+
+// Typst relative length, a ratio of 1 is 100%
+typedef struct {
+    length length;
+    float ratio;
+} relative;
+
+// Typst color in the rgb color space
+typedef struct {
+    uint8_t r;
+    uint8_t g;
+    uint8_t b;
+    uint8_t a;
+} color;
+
+// Typst color in the luma color space
+typedef struct {
+    float l;
+    float a;
+} color_luma;
+
+// Typst color in the cmyk color space
+typedef struct {
+    float c;
+    float m;
+    float y;
+    float k;
+} color_cmyk;
+
+// Typst color in the oklab color space
+typedef struct {
+    float l;
+    float a;
+    float b;
+    float alpha;
+} color_oklab;
+
+// Typst color in the oklch color space
+typedef struct {
+    float l;
+    float c;
+    float h;
+    float alpha;
+} color_oklch;
+
+// Typst color in the linear-rgb color space
 typedef struct {
+    float r;
+    float g;
+    float b;
+    float a;
+} color_linear_rgb;
+
+// Typst color in the hsl color space
+typedef struct {
+    float h;
+    float s;
+    float l;
+    float a;
+} color_hsl;
+
+// Typst color in the hsv color space
+typedef struct {
+    float h;
+    float s;
+    float v;
+    float a;
+} color_hsv;
+
+// Typst datetime with only a date
+typedef struct {
+    int32_t year;
+    uint8_t month;
+    uint8_t day;
+} date;
+
+// Typst datetime, a date and a time
+typedef struct {
+    int32_t year;
+    uint8_t month;
+    uint8_t day;
+    uint8_t hour;
+    uint8_t minute;
+    uint8_t second;
+} datetime;
+
+// Styled text, the color and size are NULL to keep the current ones
+typedef struct {
+    char* text;
+    bool bold;
+    bool italic;
+    color *color;
+    length *size;
+} text_run;
+
+typedef struct Number_t {
     float half;
     int closestInt;
     char* romanRepresentation;
     bool isNegative;
     bool isOdd;
 } Number;
+int init_Number(Number *s);
 void free_Number(Number *s);
 
-typedef struct {
-    int decimal;
-} decimalResult;
-void free_decimalResult(decimalResult *s);
-int encode_decimalResult(const decimalResult *s);
-
-typedef struct {
+typedef struct askNumber_t {
     int numberCount;
 } askNumber;
+int init_askNumber(askNumber *s);
 void free_askNumber(askNumber *s);
 int decode_askNumber(size_t buffer_len, askNumber *out);
 
-typedef struct {
+typedef struct result_t {
+    struct Number_t *numbers;
+    size_t numbers_len;
+} result;
+int init_result(result *s);
+void free_result(result *s);
+int encode_result(const result *s);
+
+typedef struct toDecimal_t {
     char* roman;
 } toDecimal;
+int init_toDecimal(toDecimal *s);
 void free_toDecimal(toDecimal *s);
 int decode_toDecimal(size_t buffer_len, toDecimal *out);
 
-typedef struct {
-    Number * numbers;
-    size_t numbers_len;
-} result;
-void free_result(result *s);
-int encode_result(const result *s);
+typedef struct decimalResult_t {
+    int decimal;
+} decimalResult;
+int init_decimalResult(decimalResult *s);
+void free_decimalResult(decimalResult *s);
+int encode_decimalResult(const decimalResult *s);
 
 #endif
@@ -9,91 +9,100 @@
 }
 
 /// Decodes a big-endian integer from the given bytes.
-#let decode-int(bytes) = {
+#let decode-int(bytes, offset) = {
   let result = 0
-  for byte in array(bytes.slice(0,4)) {
+  for byte in array(bytes.slice(offset, offset + 4)) {
     result = result * 256 + byte
   }
-  (result, 4)
-}
-
-/// Encodes a string into bytes.
-#let encode-string(value) = {
-	bytes(value) + bytes((0x00,))
-}
-
-/// Decodes a string from the given bytes.
-#let decode-string(bytes) = {
-	let length = 0
-	for byte in array(bytes) {
-		length = length + 1
-		if byte == 0x00 {
-			break
-		}
-	}
-	if length == 0 {
-		("", 1)
-	} else { 
-		(str(bytes.slice(0, length - 1)), length)
-	}
-	//(array(bytes.slice(0, length - 1)), length)
-}
-
-/// Encodes a boolean into bytes
-#let encode-bool(value) = {
-  if value {
-	bytes((0x01,))
-  } else {
-	bytes((0x00,))
+  if (result > 0x7FFFFFFF) { // the number is negative
+    result = result - 0x100000000
   }
+  (result, offset + 4)
 }
 
-/// Decodes a boolean from the given bytes
-#let decode-bool(bytes) = {
-  if bytes.at(0) == 0x00 {
-	(false, 1)
+/// Encodes an integer on `size` bytes in big-endian, using two's complement if `signed` is true.
+/// Panics if the value doesn't fit in the given size.
+#let encode-sized-int(value, size, signed) = {
+  let fits = if signed {
+    // every Typst integer fits in a signed 64 bits integer
+    size == 8 or (value >= -calc.pow(2, size * 8 - 1) and value < calc.pow(2, size * 8 - 1))
   } else {
-	(true, 1)
+    value >= 0 and (size == 8 or value < calc.pow(2, size * 8))
   }
+  if not fits {
+    let kind = if signed { "signed" } else { "unsigned" }
+    panic("Integer " + str(value) + " does not fit in a " + kind + " integer of " + str(size) + " bytes")
+  }
+  let result = ()
+  for i in range(0, size) {
+    let byte = calc.rem(value, 0x100)
+    if byte < 0 {
+      byte += 0x100
+    }
+    result.push(byte)
+    value = calc.quo(value - byte, 0x100)
+  }
+  bytes(result.rev())
 }
 
-/// Encodes a character into bytes
-#let encode-char(value) = {
-  bytes(value)
-}
-
-/// Decodes a character from the given bytes
-#let decode-char(bytes) = {
-  (bytes.at(0), 1)
+/// Decodes a big-endian integer of `size` bytes, using two's complement if `signed` is true.
+/// Panics if the value doesn't fit in a Typst integer.
+#let decode-sized-int(bytes, offset, size, signed) = {
+  let data = array(bytes.slice(offset, offset + size))
+  if not signed and size == 8 and data.at(0) >= 0x80 {
+    panic("Unsigned integer of 8 bytes does not fit in a Typst integer")
+  }
+  let result = if signed and data.at(0) >= 0x80 { data.at(0) - 0x100 } else { data.at(0) }
+  for byte in data.slice(1) {
+    result = result * 0x100 + byte
+  }
+  (result, offset + size)
 }
 
-#let fractional-to-binary(fractional_part, max_dec) = {
+#let fractional-to-binary(fractional_part, max_dec, zero) = {
 	let result = 0
-	let i = 0
-	while fractional_part != 0 and i < (23 - max_dec) {
-		fractional_part = fractional_part * 2
-		result *= 2
+	let i = 22 - max_dec
+	let first_one = 0
+	if zero {
+		while fractional_part < 1 {
+			fractional_part *= 2
+			first_one += 1
+		}
+		fractional_part -= 1
+		i = 23
+	}
+	while i > 0 and fractional_part > 0 {
+		fractional_part *= 2
 		if fractional_part >= 1 {
-			result += 1
-			fractional_part = fractional_part - 1
+			result += calc.pow(2, i - 1)
+			fractional_part -= 1
 		}
-		i += 1
+		i -= 1
 	}
-	(result, i)
+	(result, first_one)
 }
 
 #let float-to-int(value) = {
+	if value == 0 {
+		return 0
+	}
 	let sign = if value < 0.0 { 1 } else { 0 }
 	let value = calc.abs(value)
-	let integer_part = calc.trunc(value)
+	let mantissa = calc.trunc(value)
 	let fractional_part = calc.fract(value)
-	let exponent = if integer_part == 0 {0} else {calc.floor(calc.log(base: 2, integer_part))}
-	let (fractional_part, shift) = fractional-to-binary(fractional_part, exponent)
-	integer_part *= calc.pow(2, 23 - exponent)
-	integer_part += fractional_part * calc.pow(2, 23 - exponent - shift)
-	integer_part -= calc.pow(2, 23)
+	let exponent = if mantissa == 0 {
+		0
+	} else {
+		calc.floor(calc.log(base: 2, mantissa)) - 1
+	}
+	let (fractional_part, first_one) = fractional-to-binary(fractional_part, exponent, mantissa == 0)
+	mantissa *= calc.pow(2, 22 - exponent)
+	mantissa += fractional_part
+	if exponent == 0 {
+		exponent = -first_one
+	}
 	exponent += 127
-	sign * calc.pow(2, 31) + exponent * calc.pow(2, 23) + integer_part
+	return  sign * calc.pow(2, 31) + exponent * calc.pow(2, 23) + mantissa
 }
 
 #let mantissa-to-float(mantissa) = {
@@ -108,6 +117,9 @@
 }
 
 #let int-to-float(value) = {
+	if value == 0 {
+		return 0.0
+	}
 	let sign = if value >= calc.pow(2, 31) {
 		value -= calc.pow(2, 31)
 		 -1 
@@ -124,18 +136,260 @@
 	encode-int(float-to-int(value))
 }
 
+/// Decodes a float from the given bytes
+#let decode-float(bytes, offset) = {
+	let (decoded, offset) = decode-int(bytes, offset)
+	(int-to-float(decoded), offset)
+}
+
+/// Multiplies a float by 2^exponent in steps small enough to keep every intermediate result exact
+#let scale-by-pow2(value, exponent) = {
+	while exponent > 512 {
+		value *= calc.pow(2.0, 512)
+		exponent -= 512
+	}
+	while exponent < -512 {
+		value /= calc.pow(2.0, 512)
+		exponent += 512
+	}
+	if exponent >= 0 {
+		value * calc.pow(2.0, exponent)
+	} else {
+		value / calc.pow(2.0, -exponent)
+	}
+}
+
+/// Encodes a float into 8 bytes following the IEEE-754 double precision format.
+/// Only multiplications by powers of two are used so the encoding is exact.
+#let encode-double(value) = {
+	let value = float(value)
+	let negative = value < 0 or (value == 0 and repr(value).starts-with(regex("[-\u{2212}]")))
+	let magnitude = calc.abs(value)
+	let (exponent, mantissa) = if value != value {
+		(0x7FF, calc.pow(2, 51))
+	} else if magnitude > 1.7976931348623157e308 {
+		(0x7FF, 0)
+	} else if magnitude == 0 {
+		(0, 0)
+	} else {
+		let exponent = calc.floor(calc.log(magnitude, base: 2))
+		if scale-by-pow2(1.0, exponent) > magnitude {
+			exponent -= 1
+		}
+		if scale-by-pow2(1.0, exponent + 1) <= magnitude {
+			exponent += 1
+		}
+		if exponent < -1022 { // subnormal number
+			(0, int(scale-by-pow2(magnitude, 1074)))
+		} else {
+			(exponent + 1023, int(scale-by-pow2(magnitude, 52 - exponent)) - calc.pow(2, 52))
+		}
+	}
+	let sign = if negative { 0x80000000 } else { 0 }
+	let high = sign + exponent * 0x100000 + calc.quo(mantissa, 0x100000000)
+	let low = calc.rem(mantissa, 0x100000000)
+	encode-sized-int(high, 4, false) + encode-sized-int(low, 4, false)
+}
+
+/// Decodes an IEEE-754 double precision float from the given bytes
+#let decode-double(bytes, offset) = {
+	let (high, _) = decode-sized-int(bytes, offset, 4, false)
+	let (low, _) = decode-sized-int(bytes, offset + 4, 4, false)
+	let exponent = calc.rem(calc.quo(high, 0x100000), 0x800)
+	let mantissa = calc.rem(high, 0x100000) * 0x100000000 + low
+	let magnitude = if exponent == 0x7FF {
+		let infinity = 1e308 * 10.0
+		if mantissa == 0 { infinity } else { infinity - infinity }
+	} else if exponent == 0 { // subnormal number
+		scale-by-pow2(float(mantissa), -1074)
+	} else {
+		scale-by-pow2(float(mantissa + calc.pow(2, 52)), exponent - 1075)
+	}
+	(if high >= 0x80000000 { -magnitude } else { magnitude }, offset + 8)
+}
+
+#let encode-i8(value) = encode-sized-int(value, 1, true)
+#let decode-i8(bytes, offset) = decode-sized-int(bytes, offset, 1, true)
+#let encode-u8(value) = encode-sized-int(value, 1, false)
+#let decode-u8(bytes, offset) = decode-sized-int(bytes, offset, 1, false)
+#let encode-i16(value) = encode-sized-int(value, 2, true)
+#let decode-i16(bytes, offset) = decode-sized-int(bytes, offset, 2, true)
+#let encode-u16(value) = encode-sized-int(value, 2, false)
+#let decode-u16(bytes, offset) = decode-sized-int(bytes, offset, 2, false)
+#let encode-i32(value) = encode-sized-int(value, 4, true)
+#let decode-i32(bytes, offset) = decode-sized-int(bytes, offset, 4, true)
+#let encode-u32(value) = encode-sized-int(value, 4, false)
+#let decode-u32(bytes, offset) = decode-sized-int(bytes, offset, 4, false)
+#let encode-i64(value) = encode-sized-int(value, 8, true)
+#let decode-i64(bytes, offset) = decode-sized-int(bytes, offset, 8, true)
+#let encode-u64(value) = encode-sized-int(value, 8, false)
+#let decode-u64(bytes, offset) = decode-sized-int(bytes, offset, 8, false)
+
+/// Encodes a string into bytes.
+#let encode-string(value) = {
+	bytes(value) + bytes((0x00,))
+}
+
+/// Decodes a string from the given bytes.
+#let decode-string(bytes, offset) = {
+	let end = offset
+	while bytes.at(end) != 0x00 {
+		end += 1
+	}
+	(str(bytes.slice(offset, end)), end + 1)
+}
+
+/// Encodes raw bytes, prefixed by their length
+#let encode-bytes(value) = {
+	encode-int(value.len()) + value
+}
+
+/// Decodes raw bytes from the given bytes with a single slice
+#let decode-bytes(bytes, offset) = {
+	let (length, offset) = decode-int(bytes, offset)
+	(bytes.slice(offset, offset + length), offset + length)
+}
+
+/// Encodes a boolean into bytes
+#let encode-bool(value) = {
+  if value {
+	bytes((0x01,))
+  } else {
+	bytes((0x00,))
+  }
+}
+
+/// Decodes a boolean from the given bytes
+#let decode-bool(bytes, offset) = {
+  if bytes.at(offset) == 0x00 {
+	(false, offset + 1)
+  } else {
+	(true, offset + 1)
+  }
+}
+
+/// Encodes a character into bytes
+#let encode-char(value) = {
+  bytes(value)
+}
+
+/// Decodes a character from the given bytes
+#let decode-char(bytes, offset) = {
+  (bytes.at(offset), offset + 1)
+}
+
 #let encode-point(value) = {
-	encode-float(value / 1pt)
+	encode-float(value.pt())
 }
 
-/// Decodes a float from the given bytes
-#let decode-float(bytes) = {
-	(int-to-float(decode-int(bytes).at(0)), 4)
+#let decode-point(bytes, offset) = {
+	let (value, offset) = decode-float(bytes, offset)
+	(value * 1pt, offset)
 }
 
-#let decode-point(bytes) = {
-	let (value, size) = decode-float(bytes)
-	(value * 1pt, size)
+/// Encodes a length as its absolute part in points followed by its part in em
+#let encode-length(value) = {
+	encode-float(value.abs.pt()) + encode-float(value.em)
+}
+
+/// Decodes a length from the given bytes
+#let decode-length(bytes, offset) = {
+	let (abs, offset) = decode-float(bytes, offset)
+	let (em, offset) = decode-float(bytes, offset)
+	(abs * 1pt + em * 1em, offset)
+}
+
+/// Encodes an angle in degrees
+#let encode-angle(value) = {
+	encode-float(value.deg())
+}
+
+/// Decodes an angle from the given bytes
+#let decode-angle(bytes, offset) = {
+	let (value, offset) = decode-float(bytes, offset)
+	(value * 1deg, offset)
+}
+
+/// Encodes a ratio, 100% is encoded as 1
+#let encode-ratio(value) = {
+	encode-float(value / 100%)
+}
+
+/// Decodes a ratio from the given bytes
+#let decode-ratio(bytes, offset) = {
+	let (value, offset) = decode-float(bytes, offset)
+	(value * 100%, offset)
+}
+
+/// Encodes a relative length as its length followed by its ratio, lengths and ratios are accepted too
+#let encode-relative(value) = {
+	let value = value + 0pt + 0%
+	encode-length(value.length) + encode-ratio(value.ratio)
+}
+
+/// Decodes a relative length from the given bytes
+#let decode-relative(bytes, offset) = {
+	let (length, offset) = decode-length(bytes, offset)
+	let (ratio, offset) = decode-ratio(bytes, offset)
+	(length + ratio, offset)
+}
+
+/// Encodes a fraction as its number of fr
+#let encode-fraction(value) = {
+	encode-float(value / 1fr)
+}
+
+/// Decodes a fraction from the given bytes
+#let decode-fraction(bytes, offset) = {
+	let (value, offset) = decode-float(bytes, offset)
+	(value * 1fr, offset)
+}
+
+/// Encodes a color as its RGBA components, on a byte each
+#let encode-color(value) = {
+	bytes(rgb(value).components().map(c => int(calc.round(c / 100% * 255))))
+}
+
+/// Decodes a RGBA color from the given bytes
+#let decode-color(bytes, offset) = {
+	(rgb(..array(bytes.slice(offset, offset + 4))), offset + 4)
+}
+
+/// Encodes a datetime as its year on 4 bytes followed by its month, day, hour, minute and second on a byte each
+/// The time of a datetime that only has a date is encoded as midnight
+#let encode-datetime(value) = {
+	let time = (value.hour(), value.minute(), value.second()).map(x => if x == none { 0 } else { x })
+	encode-i32(value.year()) + bytes((value.month(), value.day()) + time)
+}
+
+/// Decodes a datetime from the given bytes
+#let decode-datetime(bytes, offset) = {
+	let (year, offset) = decode-i32(bytes, offset)
+	let (month, day, hour, minute, second) = array(bytes.slice(offset, offset + 5))
+	(datetime(year: year, month: month, day: day, hour: hour, minute: minute, second: second), offset + 5)
+}
+
+/// Encodes the date of a datetime as its year on 4 bytes followed by its month and day on a byte each
+#let encode-date(value) = {
+	encode-i32(value.year()) + bytes((value.month(), value.day()))
+}
+
+/// Decodes a datetime that only has a date from the given bytes
+#let decode-date(bytes, offset) = {
+	let (year, offset) = decode-i32(bytes, offset)
+	let (month, day) = array(bytes.slice(offset, offset + 2))
+	(datetime(year: year, month: month, day: day), offset + 2)
+}
+
+/// Encodes a duration as its number of seconds on 8 bytes
+#let encode-duration(value) = {
+	encode-i64(int(calc.round(value.seconds())))
+}
+
+/// Decodes a duration from the given bytes
+#let decode-duration(bytes, offset) = {
+	let (seconds, offset) = decode-i64(bytes, offset)
+	(duration(seconds: seconds), offset)
 }
 
 /// Encodes a list of elements into bytes
@@ -145,30 +399,163 @@
 	length + encoded
 }
 
+/// Encodes a list of exactly `length` elements into bytes, without its length
+#let encode-fixed-list(arr, length, encoder) = {
+	if arr.len() != length {
+		panic("Expected an array of " + str(length) + " elements but got " + str(arr.len()))
+	}
+	bytes(arr.map(encoder).map(array).flatten())
+}
+
+/// Encodes a dictionary into bytes, as its length followed by each key and value
+#let encode-map(dict, encoder) = {
+	let encoded = encode-int(dict.len())
+	for (key, value) in dict {
+		encoded += encode-string(key) + encoder(value)
+	}
+	encoded
+}
+
+/// Encodes an optional value into bytes
+#let encode-optional(opt, encoder) = {
+	if opt == none {
+		bytes((0x00,))
+	} else {
+		bytes((0x01,)) + encoder(opt)
+	}
+}
+
 /// Decodes a list of elements from the given bytes
-#let decode-list(bytes, decoder) = {
-	let (length, length_size) = decode-int(bytes)
+#let decode-list(bytes, offset, decoder) = {
+	let (length, offset) = decode-int(bytes, offset)
+	let result = ()
+	for i in range(0, length) {
+		let (element, next) = decoder(bytes, offset)
+		result.push(element)
+		offset = next
+	}
+	(result, offset)
+}
+
+/// Decodes a list of exactly `length` elements from the given bytes
+#let decode-fixed-list(bytes, offset, length, decoder) = {
 	let result = ()
-	let offset = length_size
 	for i in range(0, length) {
-		let (element, size) = decoder(bytes.slice(offset, bytes.len()))
+		let (element, next) = decoder(bytes, offset)
 		result.push(element)
-		offset += size
+		offset = next
+	}
+	(result, offset)
+}
+
+/// Decodes a dictionary from the given bytes
+#let decode-map(bytes, offset, decoder) = {
+	let (length, offset) = decode-int(bytes, offset)
+	let result = (:)
+	for i in range(0, length) {
+		let (key, next) = decode-string(bytes, offset)
+		let (value, next) = decoder(bytes, next)
+		result.insert(key, value)
+		offset = next
 	}
 	(result, offset)
 }
-#let decode-Number(bytes) = {
-  let offset = 0
-  let (f_half, size) = decode-float(bytes.slice(offset, bytes.len()))
-  offset += size
-  let (f_closestInt, size) = decode-int(bytes.slice(offset, bytes.len()))
-  offset += size
-  let (f_romanRepresentation, size) = decode-string(bytes.slice(offset, bytes.len()))
-  offset += size
-  let (f_isNegative, size) = decode-bool(bytes.slice(offset, bytes.len()))
-  offset += size
-  let (f_isOdd, size) = decode-bool(bytes.slice(offset, bytes.len()))
-  offset += size
+
+/// Decodes an optional value from the given bytes
+#let decode-optional(bytes, offset, decoder) = {
+	if bytes.at(offset) != 0x00 {
+		decoder(bytes, offset + 1)
+	} else {
+		(none, offset + 1)
+	}
+}
+
+/// Encodes the components of a color as floats, ratios are encoded between 0 and 1 and angles in degrees
+#let encode-color-components(components) = {
+	components.map(c => if type(c) == ratio { c / 100% } else if type(c) == angle { c.deg() } else { c }).map(encode-float).sum()
+}
+
+/// Decodes the components of a color with `constructor`, each component is multiplied by its unit
+#let decode-color-components(bytes, offset, constructor, units) = {
+	let (components, offset) = decode-fixed-list(bytes, offset, units.len(), decode-float)
+	(constructor(..components.zip(units).map(((c, unit)) => c * unit)), offset)
+}
+
+#let encode-color-luma(value) = encode-color-components(luma(value).components())
+#let decode-color-luma(bytes, offset) = decode-color-components(bytes, offset, luma, (100%, 100%))
+#let encode-color-cmyk(value) = encode-color-components(cmyk(value).components())
+#let decode-color-cmyk(bytes, offset) = decode-color-components(bytes, offset, cmyk, (100%, 100%, 100%, 100%))
+#let encode-color-oklab(value) = encode-color-components(oklab(value).components())
+#let decode-color-oklab(bytes, offset) = decode-color-components(bytes, offset, oklab, (100%, 1, 1, 100%))
+#let encode-color-oklch(value) = encode-color-components(oklch(value).components())
+#let decode-color-oklch(bytes, offset) = decode-color-components(bytes, offset, oklch, (100%, 1, 1deg, 100%))
+#let encode-color-linear-rgb(value) = encode-color-components(color.linear-rgb(value).components())
+#let decode-color-linear-rgb(bytes, offset) = decode-color-components(bytes, offset, color.linear-rgb, (100%, 100%, 100%, 100%))
+#let encode-color-hsl(value) = encode-color-components(color.hsl(value).components())
+#let decode-color-hsl(bytes, offset) = decode-color-components(bytes, offset, color.hsl, (1deg, 100%, 100%, 100%))
+#let encode-color-hsv(value) = encode-color-components(color.hsv(value).components())
+#let decode-color-hsv(bytes, offset) = decode-color-components(bytes, offset, color.hsv, (1deg, 100%, 100%, 100%))
+
+/// Encodes a text run, a dictionary with a `text` and optional `bold`, `italic`, `color` and `size` keys
+#let encode-text-run(value) = {
+	(
+		encode-string(value.text)
+		+ encode-bool(value.at("bold", default: false))
+		+ encode-bool(value.at("italic", default: false))
+		+ encode-optional(value.at("color", default: none), encode-color)
+		+ encode-optional(value.at("size", default: none), encode-length)
+	)
+}
+
+/// Decodes a text run from the given bytes, its color and size are none when they are not set
+#let decode-text-run(bytes, offset) = {
+	let (body, offset) = decode-string(bytes, offset)
+	let (bold, offset) = decode-bool(bytes, offset)
+	let (italic, offset) = decode-bool(bytes, offset)
+	let (fill, offset) = decode-optional(bytes, offset, decode-color)
+	let (font-size, offset) = decode-optional(bytes, offset, decode-length)
+	((text: body, bold: bold, italic: italic, color: fill, size: font-size), offset)
+}
+
+/// Renders a list of text runs as content, the runs without color or size keep the current ones
+#let render-text-runs(runs) = {
+	for run in runs {
+		let style = (:)
+		if run.at("bold", default: false) {
+			style.insert("weight", "bold")
+		}
+		if run.at("italic", default: false) {
+			style.insert("style", "italic")
+		}
+		if run.at("color", default: none) != none {
+			style.insert("fill", run.color)
+		}
+		if run.at("size", default: none) != none {
+			style.insert("size", run.size)
+		}
+		text(..style, run.text)
+	}
+}
+
+/// Panics if `value` is set and is not between `min` and `max`, `name` is the field in the message
+#let check-range(value, min, max, name) = {
+  if value != none and (value < min or value > max) {
+    panic(name + " must be between " + repr(min) + " and " + repr(max) + ", got " + repr(value))
+  }
+}
+
+/// Panics if `value` is set and its length is not between `min` and `max`, `name` is the field in the message
+#let check-len(value, min, max, name) = {
+  if value != none and (value.len() < min or value.len() > max) {
+    panic("The length of " + name + " must be between " + str(min) + " and " + str(max) + ", got " + str(value.len()))
+  }
+}
+#let decode-Number(bytes, offset) = {
+  let (f_half, offset) = decode-float(bytes, offset)
+  let (f_closestInt, offset) = decode-int(bytes, offset)
+  let (f_romanRepresentation, offset) = decode-string(bytes, offset)
+  let (f_isNegative, offset) = decode-bool(bytes, offset)
+  let (f_isOdd, offset) = decode-bool(bytes, offset)
   ((
     half: f_half,
     closestInt: f_closestInt,
@@ -177,25 +564,21 @@
     isOdd: f_isOdd,
   ), offset)
 }
-#let decode-decimalResult(bytes) = {
-  let offset = 0
-  let (f_decimal, size) = decode-int(bytes.slice(offset, bytes.len()))
-  offset += size
-  ((
-    decimal: f_decimal,
-  ), offset)
-}
 #let encode-askNumber(value) = {
   encode-int(value.at("numberCount"))
 }
+#let decode-result(bytes, offset) = {
+  let (f_numbers, offset) = decode-list(bytes, offset, decode-Number)
+  ((
+    numbers: f_numbers,
+  ), offset)
+}
 #let encode-toDecimal(value) = {
   encode-string(value.at("roman"))
 }
-#let decode-result(bytes) = {
-  let offset = 0
-  let (f_numbers, size) = decode-list(bytes.slice(offset, bytes.len()), decode-Number)
-  offset += size
+#let decode-decimalResult(bytes, offset) = {
+  let (f_decimal, offset) = decode-int(bytes, offset)
   ((
-    numbers: f_numbers,
+    decimal: f_decimal,
   ), offset)
 }
@@ -0,0 +1,23 @@
+use std::fmt::Debug;
+
+use crate::types::Types;
+
+/// Used to represent a type alias in the protocol file, like `type Length = point;`
+pub struct Alias<'a> {
+	pub type_: Types,
+	pub pos: pest::Span<'a>,
+	/// Doc comment written before the declaration
+	pub doc: Option<String>,
+}
+
+impl<'a> Alias<'a> {
+	pub fn new(type_: Types, pos: pest::Span<'a>) -> Alias<'a> {
+		Alias { type_, pos, doc: None }
+	}
+}
+
+impl<'a> Debug for Alias<'a> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Alias {:?}", self.type_)
+	}
+}
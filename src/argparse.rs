@@ -15,7 +15,19 @@ pub struct Argparse {
 	#[arg(short, conflicts_with("output_dir"), requires("c_output"))]
 	pub typst_output: Option<String>,
 
+	/// Prefix of the generated functions, overrides the package of the protocol file
+	#[arg(long)]
+	pub prefix: Option<String>,
+
+	/// Typst version targeted by the generated code, like 0.11, newer versions use faster built-in conversions
+	#[arg(long)]
+	pub typst_version: Option<String>,
+
 	/// Check if the input file is a valid protocol file
 	#[arg(long, action, conflicts_with("output_dir"), conflicts_with("c_output"), conflicts_with("typst_output"))]
-	pub check: bool
+	pub check: bool,
+
+	/// Previous version of the protocol file, implies --check and warns about the changes that break the decoding of its payloads
+	#[arg(long, conflicts_with("output_dir"), conflicts_with("c_output"), conflicts_with("typst_output"))]
+	pub previous: Option<String>,
 }
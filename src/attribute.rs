@@ -0,0 +1,66 @@
+use std::fmt::Debug;
+
+use crate::{field::Value, types::Types};
+
+/// An attribute written before a field, like `@range(0, 100)`
+pub struct Attribute<'a> {
+	pub name: &'a str,
+	pub args: Vec<Value>,
+	pub pos: pest::Span<'a>,
+}
+
+impl<'a> Attribute<'a> {
+	/// Create an attribute, the name must be a known attribute and the arguments must match it
+	pub fn new(name: &'a str, args: Vec<Value>, pos: pest::Span<'a>) -> Result<Attribute<'a>, String> {
+		match (name, args.as_slice()) {
+			("typst_name", [Value::String(s)]) if !s.is_empty() => {}
+			("typst_name", _) => return Err("Expected a non empty string: @typst_name(\"name\")".to_string()),
+			("deprecated", [] | [Value::String(_)]) => {}
+			("deprecated", _) => return Err("Expected no argument or a string: @deprecated(\"reason\")".to_string()),
+			("range", [min, max]) if min.as_number().is_some() && max.as_number().is_some() => {
+				if min.as_number() > max.as_number() {
+					return Err("The minimum of the range is greater than its maximum".to_string());
+				}
+			}
+			("range", _) => return Err("Expected two numbers: @range(min, max)".to_string()),
+			("c_type", [Value::String(s)]) if !s.is_empty() => {}
+			("c_type", _) => return Err("Expected a non empty string: @c_type(\"type\")".to_string()),
+			("since", [Value::Int(version)]) if (1..=i32::MAX as i128).contains(version) => {}
+			("since", _) => return Err("Expected a positive integer: @since(version)".to_string()),
+			_ => return Err(format!("Unknown attribute \"{}\"", name)),
+		}
+		Ok(Attribute { name, args, pos })
+	}
+
+	/// Check that the attribute can be used on a field of the given type
+	/// `@range` is checked as a range constraint
+	pub fn check_type(&self, t: &Types) -> Result<(), String> {
+		let t = t.resolved();
+		match self.name {
+			"c_type" if !matches!(
+				t,
+				Types::Int | Types::Integer { .. } | Types::Float | Types::Double | Types::Point | Types::Bool | Types::Char | Types::Enum(_)
+			) => Err("@c_type can only be used on numbers, booleans, chars and enums".to_string()),
+			_ => Ok(()),
+		}
+	}
+
+	/// String argument of the attribute, if its first argument is a string
+	pub fn string(&self) -> Option<&str> {
+		match self.args.first() {
+			Some(Value::String(s)) => Some(s),
+			_ => None,
+		}
+	}
+}
+
+impl<'a> Debug for Attribute<'a> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "@{}", self.name)?;
+		if !self.args.is_empty() {
+			let args = self.args.iter().map(|arg| format!("{:?}", arg)).collect::<Vec<_>>();
+			write!(f, "({})", args.join(", "))?;
+		}
+		Ok(())
+	}
+}
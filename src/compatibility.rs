@@ -0,0 +1,94 @@
+use crate::{struct_::StructType, types::Types, Field, Protocol, Struct};
+
+/// Name of the encoding of a type, aliases are encoded like the type they name
+fn encoding(t: &Types) -> String {
+	match t {
+		Types::Alias(_, t) => encoding(t),
+		Types::Array(t) => format!("{}[]", encoding(t)),
+		Types::FixedArray(t, size) => format!("{}[{}]", encoding(t), size),
+		Types::Optional(t) => format!("{}?", encoding(t)),
+		Types::Map(t) => format!("map<string, {}>", encoding(t)),
+		_ => format!("{:?}", t),
+	}
+}
+
+/// Compare the fields of a struct, union or protocol with their previous version
+/// Fields can only be added at the end of unions, and of protocols when they are marked with `@since`
+fn check_fields(name: &str, previous: &Struct, current: &Struct, warnings: &mut Vec<String>) {
+	for (index, old) in previous.iter().enumerate() {
+		let Some(new) = current.fields().get(index) else {
+			warnings.push(format!("Field \"{}.{}\" was removed, mark it with @deprecated instead", name, old.name));
+			continue;
+		};
+		// renaming a field doesn't change the encoding
+		let renamed = !previous.has_field(new.name) && !current.has_field(old.name);
+		if new.name != old.name && !renamed {
+			warnings.push(format!(
+				"Field \"{}.{}\" was moved, fields are decoded in the order they are declared",
+				name, old.name
+			));
+		} else if encoding(&new.type_) != encoding(&old.type_) {
+			warnings.push(format!("Field \"{}.{}\" changed from {:?} to {:?}", name, new.name, old.type_, new.type_));
+		}
+	}
+	for Field { name: field, .. } in current.iter().skip(previous.fields().len()).filter(|field| field.since().is_none()) {
+		match current.get_type() {
+			StructType::Union => {}
+			StructType::Protocol(_) => warnings.push(format!(
+				"Field \"{}.{}\" was added without @since, payloads of the previous version can't be decoded",
+				name, field
+			)),
+			StructType::Struct => warnings.push(format!(
+				"Field \"{}.{}\" was added, the encoding of a struct can't change",
+				name, field
+			)),
+		}
+	}
+}
+
+/// Compare a protocol file with its previous version and return a warning for each change
+/// that prevents the decoders generated from `current` from reading the payloads encoded with `previous`
+pub fn check_compatibility(previous: &Protocol, current: &Protocol) -> Vec<String> {
+	let mut warnings = Vec::new();
+	for (name, old) in previous.ordered_enums() {
+		let Some(new) = current.get_enum(name) else {
+			continue;
+		};
+		for (old, new) in old.iter().zip(new.iter()) {
+			if old.name != new.name {
+				warnings.push(format!(
+					"Variant \"{}.{}\" was replaced by \"{}\", variants are encoded as their index",
+					name, old.name, new.name
+				));
+			}
+		}
+		if new.len() < old.len() {
+			warnings.push(format!("Variants of the enum \"{}\" were removed", name));
+		}
+	}
+	for (name, old) in previous.ordered_structs() {
+		if let Some(new) = current.get_struct(name) {
+			check_fields(name, old, new, &mut warnings);
+		}
+	}
+	for (name, old) in previous.protocols() {
+		let Some(new) = current.get_protocol(name) else {
+			warnings.push(format!("Protocol \"{}\" was removed", name));
+			continue;
+		};
+		check_fields(name, old, new, &mut warnings);
+		match (old.version, new.version, new.oldest_version()) {
+			(Some(_), None, _) => warnings.push(format!("The version of the protocol \"{}\" was removed", name)),
+			(None, Some(_), _) => warnings.push(format!(
+				"A version was added to the protocol \"{}\", payloads of the previous version don't have it",
+				name
+			)),
+			(Some(old), Some(_), Some(oldest)) if old < oldest => warnings.push(format!(
+				"The protocol \"{}\" doesn't accept its previous version {}, mark the fields added since with @since({})",
+				name, old, old + 1
+			)),
+			_ => {}
+		}
+	}
+	warnings
+}
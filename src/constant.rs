@@ -0,0 +1,25 @@
+use std::fmt::Debug;
+
+use crate::{field::Value, types::Types};
+
+/// Used to represent a constant in the protocol file, like `const int MAX_POINTS = 256;`
+pub struct Constant<'a> {
+	pub type_: Types,
+	/// Value of the constant, already checked against its type
+	pub value: Value,
+	pub pos: pest::Span<'a>,
+	/// Doc comment written before the declaration
+	pub doc: Option<String>,
+}
+
+impl<'a> Constant<'a> {
+	pub fn new(type_: Types, value: Value, pos: pest::Span<'a>) -> Constant<'a> {
+		Constant { type_, value, pos, doc: None }
+	}
+}
+
+impl<'a> Debug for Constant<'a> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Constant {:?} = {:?}", self.type_, self.value)
+	}
+}
@@ -0,0 +1,91 @@
+use std::fmt::Debug;
+
+use crate::{field::Value, types::Types};
+
+/// What a constraint checks
+pub enum ConstraintKind {
+	/// `in min..max`, the value must be between `min` and `max` included
+	Range(Value, Value),
+	/// `len min..max` or `max N`, the length must be between `min` and `max` included
+	Length(usize, usize),
+}
+
+/// A constraint written after a field, like `int level in 1..6;`
+/// Constraints are checked by the Typst encoder and the C decoder
+pub struct Constraint<'a> {
+	pub kind: ConstraintKind,
+	pub pos: pest::Span<'a>,
+}
+
+/// Type whose values are checked, optionals are only checked when they are set
+pub fn checked_type(t: &Types) -> &Types {
+	match t.resolved() {
+		Types::Optional(t) => checked_type(t),
+		t => t,
+	}
+}
+
+impl<'a> Constraint<'a> {
+	/// Create a range constraint, the bounds must be numbers
+	pub fn range(min: Value, max: Value, pos: pest::Span<'a>) -> Result<Constraint<'a>, String> {
+		match (min.as_number(), max.as_number()) {
+			(Some(a), Some(b)) if a > b => Err("The minimum of the range is greater than its maximum".to_string()),
+			(Some(_), Some(_)) => Ok(Constraint { kind: ConstraintKind::Range(min, max), pos }),
+			_ => Err("Expected two numbers: in min..max".to_string()),
+		}
+	}
+
+	/// Create a length constraint, the bounds must be positive integers
+	pub fn length(min: Value, max: Value, pos: pest::Span<'a>) -> Result<Constraint<'a>, String> {
+		let bound = |value: Value| match value {
+			Value::Int(i) => usize::try_from(i).ok(),
+			_ => None,
+		};
+		match (bound(min), bound(max)) {
+			(Some(min), Some(max)) if min > max => Err("The minimum length is greater than the maximum".to_string()),
+			(Some(min), Some(max)) => Ok(Constraint { kind: ConstraintKind::Length(min, max), pos }),
+			_ => Err("Expected positive integers as length".to_string()),
+		}
+	}
+
+	/// Check that the constraint can be used on a field of the given type
+	/// The bounds of a range are converted to the type of the field
+	pub fn check_type(&mut self, t: &Types) -> Result<(), String> {
+		let t = checked_type(t);
+		match &mut self.kind {
+			ConstraintKind::Range(min, max) => {
+				if !matches!(t, Types::Int | Types::Integer { .. } | Types::Float | Types::Double | Types::Point) {
+					return Err("A range can only be checked on numbers".to_string());
+				}
+				*min = min.clone().resolve(t, None)?;
+				*max = max.clone().resolve(t, None)?;
+			}
+			ConstraintKind::Length(..) => {
+				if !matches!(t, Types::String | Types::Bytes | Types::Array(_) | Types::Map(_)) {
+					return Err("A length can only be checked on strings, bytes, lists and maps".to_string());
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Check if a value written in the protocol file respects the constraint
+	pub fn allows(&self, value: &Value) -> bool {
+		match (&self.kind, value) {
+			(ConstraintKind::Range(min, max), value) => {
+				value.as_number() >= min.as_number() && value.as_number() <= max.as_number()
+			}
+			(ConstraintKind::Length(min, max), Value::String(s)) => s.len() >= *min && s.len() <= *max,
+			_ => true,
+		}
+	}
+}
+
+impl<'a> Debug for Constraint<'a> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.kind {
+			ConstraintKind::Range(min, max) => write!(f, "in {:?}..{:?}", min, max),
+			ConstraintKind::Length(min, max) => write!(f, "len {}..{}", min, max),
+		}
+	}
+}
@@ -0,0 +1,66 @@
+use std::{collections::HashSet, fmt::Debug};
+
+/// A variant of an enum
+pub struct Variant<'a> {
+	pub name: &'a str,
+	pub pos: pest::Span<'a>,
+	/// Doc comment written before the variant
+	pub doc: Option<String>,
+}
+
+/// Used to represent an enum in the protocol file
+/// Each variant is encoded as its index on a single byte
+pub struct Enum<'a> {
+	pos: pest::Span<'a>,
+	/// Doc comment written before the declaration
+	pub doc: Option<String>,
+	variants_names: HashSet<&'a str>,
+	// variants are stored in a vector because their index is their value on the wire
+	variants: Vec<Variant<'a>>,
+}
+
+impl<'a> Enum<'a> {
+	pub fn new(pos: pest::Span<'a>) -> Enum<'a> {
+		Enum {
+			pos,
+			doc: None,
+			variants_names: HashSet::new(),
+			variants: Vec::new(),
+		}
+	}
+
+	pub fn add_variant(&mut self, name: &'a str, pos: pest::Span<'a>, doc: Option<String>) {
+		self.variants.push(Variant { name, pos, doc });
+		self.variants_names.insert(name);
+	}
+
+	pub fn has_variant(&self, name: &str) -> bool {
+		self.variants_names.contains(name)
+	}
+
+	pub fn iter<'b>(&'b self) -> std::slice::Iter<'b, Variant<'a>> {
+		self.variants.iter()
+	}
+
+	pub fn len(&self) -> usize {
+		self.variants.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.variants.is_empty()
+	}
+
+	pub fn get_pos(&self) -> pest::Span<'a> {
+		self.pos
+	}
+}
+
+impl<'a> Debug for Enum<'a> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Enum {{")?;
+		for variant in &self.variants {
+			write!(f, "\n\t{}", variant.name)?;
+		}
+		write!(f, "\n}}")
+	}
+}
@@ -0,0 +1,188 @@
+use std::fmt::Debug;
+
+use crate::{attribute::Attribute, constraint::Constraint, types::Types, Enum};
+
+/// A value written in the protocol file, used as default value of a field, as attribute argument or as value of a constant
+#[derive(Clone)]
+pub enum Value {
+	Int(i128),
+	Float(f64),
+	Bool(bool),
+	Char(char),
+	String(String),
+	/// An identifier, the name of the variant of the enum type of the field for default values
+	Enum(String),
+}
+
+/// Escape a string so it can be written between double quotes in C or Typst
+pub(crate) fn escape_string(s: &str) -> String {
+	let mut escaped = String::new();
+	for c in s.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			'\r' => escaped.push_str("\\r"),
+			'\t' => escaped.push_str("\\t"),
+			_ => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+impl Value {
+	/// Value of a number as a float, `None` if the value is not a number
+	pub fn as_number(&self) -> Option<f64> {
+		match self {
+			Value::Int(i) => Some(*i as f64),
+			Value::Float(f) => Some(*f),
+			_ => None,
+		}
+	}
+
+	/// Check that the value can be used for a field of the given type and convert it if needed
+	/// The enum of the field is needed to check that the variant exists
+	pub fn resolve(self, t: &Types, enum_: Option<&Enum>) -> Result<Value, String> {
+		match (self, t) {
+			(value, Types::Alias(_, t)) => value.resolve(t, enum_),
+			(Value::Int(i), Types::Int | Types::Integer { .. }) => {
+				let (min, max) = match t {
+					Types::Integer { bytes, signed: true } => (-(1i128 << (bytes * 8 - 1)), (1i128 << (bytes * 8 - 1)) - 1),
+					// Typst integers can't hold u64 values above the i64 range
+					Types::Integer { bytes: 8, signed: false } => (0, i64::MAX as i128),
+					Types::Integer { bytes, signed: false } => (0, (1i128 << (bytes * 8)) - 1),
+					_ => (i32::MIN as i128, i32::MAX as i128),
+				};
+				if i < min || i > max {
+					return Err(format!("Expected an integer between {} and {}", min, max));
+				}
+				Ok(Value::Int(i))
+			}
+			(_, Types::Int | Types::Integer { .. }) => Err("Expected an integer".to_string()),
+			(Value::Int(i), Types::Float | Types::Double | Types::Point) => Ok(Value::Float(i as f64)),
+			// a 32 bits float would overflow to infinity
+			(Value::Float(f), Types::Float | Types::Point) if f.abs() > f32::MAX as f64 => {
+				Err(format!("Expected a number between {:e} and {:e}", f32::MIN, f32::MAX))
+			}
+			(Value::Float(f), Types::Float | Types::Double | Types::Point) => Ok(Value::Float(f)),
+			(_, Types::Float | Types::Double | Types::Point) => Err("Expected a number".to_string()),
+			(Value::Bool(b), Types::Bool) => Ok(Value::Bool(b)),
+			(_, Types::Bool) => Err("Expected true or false".to_string()),
+			(Value::String(s), Types::String) => Ok(Value::String(s)),
+			(_, Types::String) => Err("Expected a string".to_string()),
+			(Value::String(s), Types::Char) => {
+				let mut chars = s.chars();
+				match (chars.next(), chars.next()) {
+					(Some(c), None) if c.is_ascii() => Ok(Value::Char(c)),
+					_ => Err("Expected a single ASCII character".to_string()),
+				}
+			}
+			(_, Types::Char) => Err("Expected a single ASCII character".to_string()),
+			(Value::Enum(variant), Types::Enum(_)) if enum_.is_some_and(|e| e.has_variant(&variant)) => {
+				Ok(Value::Enum(variant))
+			}
+			(_, Types::Enum(name)) => Err(format!("Expected a variant of the enum \"{}\"", name)),
+			_ => Err(format!("Fields of type {:?} cannot have a default value", t)),
+		}
+	}
+
+	/// C expression of the value for a field of the given type, enum variants are prefixed by `prefix` like their enum
+	pub fn to_c(&self, t: &Types, prefix: &str) -> String {
+		let t = t.resolved();
+		match self {
+			Value::Int(i) => match t {
+				Types::Integer { bytes: 8, signed: false } => format!("UINT64_C({})", i),
+				Types::Integer { bytes: 8, signed: true } if *i == i64::MIN as i128 => "INT64_MIN".to_string(),
+				Types::Integer { bytes: 8, signed: true } => format!("INT64_C({})", i),
+				_ if *i < 0 => format!("({})", i),
+				_ => i.to_string(),
+			},
+			Value::Float(f) if f.is_sign_negative() => format!("({:?})", f),
+			Value::Float(f) => format!("{:?}", f),
+			Value::Bool(b) => b.to_string(),
+			Value::Char(c) => match c {
+				'\'' => "'\\''".to_string(),
+				'\\' => "'\\\\'".to_string(),
+				_ => format!("'{}'", escape_string(&c.to_string())),
+			},
+			Value::String(s) => format!("\"{}\"", escape_string(s)),
+			Value::Enum(variant) => format!("{}{}_{}", prefix, t.to_c(false), variant),
+		}
+	}
+
+	/// Typst expression of the value for a field of the given type
+	pub fn to_typst(&self, t: &Types) -> String {
+		match self {
+			// Typst reads 9223372036854775808 as a float as it doesn't fit in an integer
+			Value::Int(i) if *i == i64::MIN as i128 => "(-9223372036854775807 - 1)".to_string(),
+			Value::Int(i) => i.to_string(),
+			Value::Float(f) => match t.resolved() {
+				Types::Point => format!("{:?}pt", f),
+				_ => format!("{:?}", f),
+			},
+			Value::Bool(b) => b.to_string(),
+			Value::Char(c) => format!("\"{}\"", escape_string(&c.to_string())),
+			Value::String(s) | Value::Enum(s) => format!("\"{}\"", escape_string(s)),
+		}
+	}
+}
+
+impl Debug for Value {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Value::Int(i) => write!(f, "{}", i),
+			Value::Float(x) => write!(f, "{:?}", x),
+			Value::Bool(b) => write!(f, "{}", b),
+			Value::Char(c) => write!(f, "{:?}", c.to_string()),
+			Value::String(s) => write!(f, "{:?}", s),
+			Value::Enum(variant) => write!(f, "{}", variant),
+		}
+	}
+}
+
+/// A field of a struct, union or protocol
+pub struct Field<'a> {
+	pub name: &'a str,
+	pub type_: Types,
+	pub pos: pest::Span<'a>,
+	pub default: Option<Value>,
+	pub default_pos: Option<pest::Span<'a>>,
+	/// Doc comment written before the field
+	pub doc: Option<String>,
+	pub attributes: Vec<Attribute<'a>>,
+	/// Constraints on the values of the field, including the one given by `@range`
+	pub constraints: Vec<Constraint<'a>>,
+}
+
+impl<'a> Field<'a> {
+	pub fn new(name: &'a str, type_: Types, pos: pest::Span<'a>) -> Field<'a> {
+		Field {
+			name,
+			type_,
+			pos,
+			default: None,
+			default_pos: None,
+			doc: None,
+			attributes: Vec::new(),
+			constraints: Vec::new(),
+		}
+	}
+
+	/// Return the attribute with the given name if the field has it
+	pub fn attribute(&self, name: &str) -> Option<&Attribute<'a>> {
+		self.attributes.iter().find(|attribute| attribute.name == name)
+	}
+
+	/// Name of the field in the Typst dictionaries, it can be changed with `@typst_name`
+	pub fn typst_name(&self) -> &str {
+		self.attribute("typst_name").and_then(|attribute| attribute.string()).unwrap_or(self.name)
+	}
+
+	/// Version of the protocol that added the field, given with `@since`
+	pub fn since(&self) -> Option<u32> {
+		match self.attribute("since")?.args.first() {
+			Some(Value::Int(version)) => Some(*version as u32),
+			_ => None,
+		}
+	}
+}
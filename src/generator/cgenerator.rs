@@ -1,8 +1,14 @@
-use crate::{protocol::Protocol, struct_::StructType, types::Types, Struct};
-use std::{fs, io::Write};
+use crate::{
+    constraint::{Constraint, ConstraintKind},
+    protocol::Protocol,
+    struct_::StructType,
+    types::{ColorSpace, Types},
+    Enum, Field, Struct,
+};
+use std::{collections::HashSet, fs, io::Write};
 
-const HEADER: &str = "#ifndef PROTOCOL_H
-#define PROTOCOL_H
+const HEADER: &str = "#ifndef $$PROTOCOL_H
+#define $$PROTOCOL_H
 
 #include <stdlib.h>
 #include <stdint.h>
@@ -18,11 +24,17 @@ const HEADER: &str = "#ifndef PROTOCOL_H
 PROTOCOL_FUNCTION void wasm_minimal_protocol_send_result_to_host(const uint8_t *ptr, size_t len);
 PROTOCOL_FUNCTION void wasm_minimal_protocol_write_args_to_buffer(uint8_t *ptr);
 
-typedef size_t (*size_function)(const void*);
+typedef struct $bytes_t {
+    uint8_t *data;
+    size_t len;
+} $bytes;
 
-#define TYPST_INT_SIZE 4
+// Index of key in the keys of a map or -1 if the key is not in the map
+int $map_find(char *const *keys, size_t len, const char *key);
 
-#define INIT_BUFFER_UNPACK(buffer_len)                                                             \\
+#define $$TYPST_INT_SIZE 4
+
+#define $$INIT_BUFFER_UNPACK(buffer_len)                                                           \\
     size_t __buffer_offset = 0;                                                                    \\
     uint8_t *__input_buffer = malloc((buffer_len));                                                \\
     if (!__input_buffer) {                                                                         \\
@@ -30,13 +42,13 @@ typedef size_t (*size_function)(const void*);
     }                                                                                              \\
     wasm_minimal_protocol_write_args_to_buffer(__input_buffer);
 
-#define CHECK_BUFFER()                                                                             \\
+#define $$CHECK_BUFFER()                                                                           \\
 	if (__buffer_offset >= buffer_len) {                                                           \\
 		return 2;                                                                                  \\
 	}
 
-#define NEXT_STR(dst)                                                                              \\
-	CHECK_BUFFER()                                                                                 \\
+#define $$NEXT_STR(dst)                                                                            \\
+	$$CHECK_BUFFER()                                                                               \\
     {                                                                                              \\
 		if (__input_buffer[__buffer_offset] == '\\0') {                                            \\
 			(dst) = malloc(1);                                                                     \\
@@ -56,54 +68,101 @@ typedef size_t (*size_function)(const void*);
 		}                                                                                          \\
     }
 
-#define NEXT_INT(dst)                                                                              \\
-	CHECK_BUFFER()                                                                                 \\
-    (dst) = big_endian_decode(__input_buffer + __buffer_offset, TYPST_INT_SIZE);                   \\
-    __buffer_offset += TYPST_INT_SIZE;
+#define $$NEXT_INT(dst)                                                                            \\
+	$$CHECK_BUFFER()                                                                               \\
+    (dst) = $big_endian_decode(__input_buffer + __buffer_offset, $$TYPST_INT_SIZE);                \\
+    __buffer_offset += $$TYPST_INT_SIZE;
+
+#define $$NEXT_DOUBLE(dst)                                                                         \\
+	$$CHECK_BUFFER()                                                                               \\
+    (dst) = $decode_double(__input_buffer + __buffer_offset);                                      \\
+	__buffer_offset += 8;
+
+#define $$NEXT_SIZED_INT(dst, size)                                                                \\
+	$$CHECK_BUFFER()                                                                               \\
+    (dst) = $big_endian_decode_u64(__input_buffer + __buffer_offset, (size));                      \\
+    __buffer_offset += (size);
 
-#define NEXT_CHAR(dst)                                                                             \\
-	CHECK_BUFFER()                                                                                 \\
+#define $$NEXT_BYTES(dst, len)                                                                     \\
+	if (__buffer_offset + (len) > buffer_len) {                                                    \\
+		return 2;                                                                                  \\
+	}                                                                                              \\
+	if ((len) == 0) {                                                                              \\
+		(dst) = NULL;                                                                              \\
+	} else {                                                                                       \\
+		(dst) = malloc((len));                                                                     \\
+		if (!(dst)) {                                                                              \\
+			return 1;                                                                              \\
+		}                                                                                          \\
+		memcpy((dst), __input_buffer + __buffer_offset, (len));                                    \\
+		__buffer_offset += (len);                                                                  \\
+	}
+
+#define $$NEXT_CHAR(dst)                                                                           \\
+	$$CHECK_BUFFER()                                                                               \\
+    (dst) = __input_buffer[__buffer_offset++];
+
+#define $$NEXT_ENUM(dst, count)                                                                    \\
+	$$CHECK_BUFFER()                                                                               \\
+	if (__input_buffer[__buffer_offset] >= (count)) {                                              \\
+		return 3;                                                                                  \\
+	}                                                                                              \\
     (dst) = __input_buffer[__buffer_offset++];
 
-#define NEXT_FLOAT(dst)                                                                            \\
-	CHECK_BUFFER()                                                                                 \\
-    (dst) = decode_float(__input_buffer + __buffer_offset);                                        \\
-	__buffer_offset += TYPST_INT_SIZE;
+#define $$NEXT_FLOAT(dst)                                                                          \\
+	$$CHECK_BUFFER()                                                                               \\
+    (dst) = $decode_float(__input_buffer + __buffer_offset);                                       \\
+	__buffer_offset += $$TYPST_INT_SIZE;
     
-#define FREE_BUFFER()                                                                              \\
+#define $$FREE_BUFFER()                                                                            \\
     free(__input_buffer);                                                                          \\
     __input_buffer = NULL;
 
-#define INIT_BUFFER_PACK(buffer_len)                                                               \\
+#define $$INIT_BUFFER_PACK(buffer_len)                                                             \\
     size_t __buffer_offset = 0;                                                                    \\
     uint8_t *__input_buffer = malloc((buffer_len));                                                \\
     if (!__input_buffer) {                                                                         \\
         return 1;                                                                                  \\
     }
 
-#define FLOAT_PACK(fp)                                                                             \\
+#define $$FLOAT_PACK(fp)                                                                           \\
     {                                                                                              \\
 		if (fp == 0.0f) {  																	       \\
-			big_endian_encode(0, __input_buffer + __buffer_offset, TYPST_INT_SIZE);                \\
+			$big_endian_encode(0, __input_buffer + __buffer_offset, $$TYPST_INT_SIZE);             \\
 		} else {                                                                                   \\
 			union FloatBuffer { 																   \\
 				float f;   																	       \\
 				int i;   																	       \\
 			} __float_buffer;                                                                      \\
 			__float_buffer.f = (fp);                                                               \\
-			big_endian_encode(__float_buffer.i, __input_buffer + __buffer_offset, TYPST_INT_SIZE); \\
+			$big_endian_encode(__float_buffer.i, __input_buffer + __buffer_offset, $$TYPST_INT_SIZE); \\
 		}                                                                                          \\
-		__buffer_offset += TYPST_INT_SIZE;                                                         \\
+		__buffer_offset += $$TYPST_INT_SIZE;                                                       \\
 	}
 
-#define INT_PACK(i)                                                                                \\
-    big_endian_encode((i), __input_buffer + __buffer_offset, TYPST_INT_SIZE);                      \\
-    __buffer_offset += TYPST_INT_SIZE;
+#define $$INT_PACK(i)                                                                              \\
+    $big_endian_encode((i), __input_buffer + __buffer_offset, $$TYPST_INT_SIZE);                   \\
+    __buffer_offset += $$TYPST_INT_SIZE;
+
+#define $$DOUBLE_PACK(d)                                                                           \\
+    $encode_double((d), __input_buffer + __buffer_offset);                                         \\
+    __buffer_offset += 8;
+
+#define $$SIZED_INT_PACK(i, size)                                                                  \\
+    $big_endian_encode_u64((uint64_t)(i), __input_buffer + __buffer_offset, (size));              \\
+    __buffer_offset += (size);
 
-#define CHAR_PACK(c)                                                                               \\
+#define $$BYTES_PACK(b, len)                                                                       \\
+    $$INT_PACK(len)                                                                                \\
+    if ((len) > 0) {                                                                               \\
+        memcpy(__input_buffer + __buffer_offset, (b), (len));                                      \\
+        __buffer_offset += (len);                                                                  \\
+    }
+
+#define $$CHAR_PACK(c)                                                                             \\
     __input_buffer[__buffer_offset++] = (c);
 
-#define STR_PACK(s)                                                                                \\
+#define $$STR_PACK(s)                                                                              \\
     if (s == NULL || s[0] == '\\0') {                                                              \\
         __input_buffer[__buffer_offset++] = '\\0';                                                 \\
     } else {                                                                                       \\
@@ -115,7 +174,7 @@ typedef size_t (*size_function)(const void*);
 ";
 
 const C: &str = "#include \"protocol.h\"
-int big_endian_decode(uint8_t const *buffer, int size){
+int $big_endian_decode(uint8_t const *buffer, int size){
     int value = 0;
     for (int i = 0; i < size; i++) {
         value |= buffer[i] << (8 * (size - i - 1));
@@ -123,14 +182,28 @@ int big_endian_decode(uint8_t const *buffer, int size){
     return value;
 }
 
-void big_endian_encode(int value, uint8_t *buffer, int size) {
+void $big_endian_encode(int value, uint8_t *buffer, int size) {
     for (int i = 0; i < sizeof(int); i++) {
         buffer[i] = (value >> (8 * (sizeof(int) - i - 1))) & 0xFF;
     }
 }
 
-float decode_float(uint8_t *buffer) {
-	int value = big_endian_decode(buffer, TYPST_INT_SIZE);
+uint64_t $big_endian_decode_u64(uint8_t const *buffer, int size) {
+    uint64_t value = 0;
+    for (int i = 0; i < size; i++) {
+        value = (value << 8) | buffer[i];
+    }
+    return value;
+}
+
+void $big_endian_encode_u64(uint64_t value, uint8_t *buffer, int size) {
+    for (int i = 0; i < size; i++) {
+        buffer[i] = (value >> (8 * (size - i - 1))) & 0xFF;
+    }
+}
+
+float $decode_float(uint8_t *buffer) {
+	int value = $big_endian_decode(buffer, $$TYPST_INT_SIZE);
 	if (value == 0) {
 		return 0.0f;
 	}
@@ -142,82 +215,591 @@ float decode_float(uint8_t *buffer) {
 	return float_buffer.f;
 }
 
-void encode_float(float value, uint8_t *buffer) {
+void $encode_float(float value, uint8_t *buffer) {
 	if (value == 0.0f) {
-		big_endian_encode(0, buffer, TYPST_INT_SIZE);
+		$big_endian_encode(0, buffer, $$TYPST_INT_SIZE);
 	} else {
 		union FloatBuffer {
 			float f;
 			int i;
 		} float_buffer;
 		float_buffer.f = value;
-		big_endian_encode(float_buffer.i, buffer, TYPST_INT_SIZE);
+		$big_endian_encode(float_buffer.i, buffer, $$TYPST_INT_SIZE);
 	}
 }
 
-size_t list_size(void *list, size_t size, size_function sf, size_t element_size) {
-    size_t result = 0;
-    for (int i = 0; i < size; i++) {
-        result += sf(list + i * element_size);
-    }
-    return result;
+double $decode_double(uint8_t *buffer) {
+	union DoubleBuffer {
+		double d;
+		uint64_t i;
+	} double_buffer;
+	double_buffer.i = $big_endian_decode_u64(buffer, 8);
+	return double_buffer.d;
 }
 
-size_t optional_size(void *opt, size_function sf) {
-    return 1 + (opt ? sf(opt) : 0);
+void $encode_double(double value, uint8_t *buffer) {
+	union DoubleBuffer {
+		double d;
+		uint64_t i;
+	} double_buffer;
+	double_buffer.d = value;
+	$big_endian_encode_u64(double_buffer.i, buffer, 8);
 }
 
-size_t int_size(const void* elem) {
-    return TYPST_INT_SIZE;
-}
-size_t float_size(const void *elem) {
-    return TYPST_INT_SIZE;
-}
-size_t bool_size(const void *elem) {
-    return TYPST_INT_SIZE;
-}
-size_t char_size(const void *elem) {
-    return 1;
+int $map_find(char *const *keys, size_t len, const char *key) {
+    for (size_t i = 0; i < len; i++) {
+        if (strcmp(keys[i], key) == 0) {
+            return i;
+        }
+    }
+    return -1;
 }
-size_t string_size(const void *elem) {
+
+size_t $string_size(const void *elem) {
     if (!elem || !((char *)elem)[0]) {
         return 1;
     }
     return strlen((char *)elem) + 1;
 }
-size_t string_list_size(char **list, size_t size) {
-	size_t result = 0;
-	for (size_t i = 0; i < size; i++) {
-		result += string_size(list[i]);
-	}
-	return result;
-}
 
 ";
 
+/// Prefixes of the generated functions and macros, given by the package of the protocol
+/// `$` and `$$` in the helpers code are replaced by the function and macro prefixes
+struct Prefix {
+    /// Prefix of the functions, like `foo_`
+    function: String,
+    /// Prefix of the macros, like `FOO_`
+    macro_: String,
+}
+
+impl Prefix {
+    fn new(package: Option<&str>) -> Prefix {
+        match package {
+            Some(package) => Prefix {
+                function: format!("{}_", package),
+                macro_: format!("{}_", package.to_uppercase()),
+            },
+            None => Prefix {
+                function: String::new(),
+                macro_: String::new(),
+            },
+        }
+    }
+
+    /// C name of a type declared in the protocol
+    fn type_name(&self, name: &str) -> String {
+        format!("{}{}", self.function, name)
+    }
+
+    /// Replace the prefix markers of the helpers code
+    fn apply(&self, code: &str) -> String {
+        code.replace("$$", &self.macro_).replace('$', &self.function)
+    }
+}
+
 /// Write the header of the generated .h file
-fn generate_header(h_file: &mut fs::File) -> Result<(), std::io::Error> {
-    h_file.write(HEADER.as_bytes())?;
+fn generate_header(h_file: &mut fs::File, prefix: &Prefix) -> Result<(), std::io::Error> {
+    h_file.write_all(prefix.apply(HEADER).as_bytes())?;
     Ok(())
 }
 
 /// Write the footer of the generated .h file
 fn generate_footer(h_file: &mut fs::File) -> Result<(), std::io::Error> {
-    h_file.write(b"#endif\n")?;
+    h_file.write_all(b"#endif\n")?;
+    Ok(())
+}
+
+/// Write a doc comment in the Doxygen format
+fn generate_doc(h_file: &mut fs::File, doc: &Option<String>, level: usize) -> Result<(), std::io::Error> {
+    if let Some(doc) = doc {
+        let tab = indent(level);
+        h_file.write_all(format!("{}/**\n", tab).as_bytes())?;
+        for line in doc.lines() {
+            // the end of the comment can't appear in its content
+            let line = format!("{} * {}", tab, line.replace("*/", "* /"));
+            h_file.write_all(format!("{}\n", line.trim_end()).as_bytes())?;
+        }
+        h_file.write_all(format!("{} */\n", tab).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Doc of a field in the generated .h file, deprecated fields and the version of the fields added with `@since` are marked
+fn field_doc(field: &Field) -> Option<String> {
+    let deprecated = field.attribute("deprecated").map(|attribute| match attribute.string() {
+        Some(reason) => format!("@deprecated {}", reason),
+        None => "@deprecated".to_string(),
+    });
+    let since = field.since().map(|version| format!("@since {}", version));
+    let lines = [field.doc.clone(), deprecated, since].into_iter().flatten().collect::<Vec<_>>();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// Write an enum definition in the generated .h file
+/// Variants are prefixed by the enum name to avoid name clashes
+fn generate_enum(h_file: &mut fs::File, name: &str, e: &Enum, prefix: &Prefix) -> Result<(), std::io::Error> {
+    let type_name = prefix.type_name(name);
+    generate_doc(h_file, &e.doc, 0)?;
+    h_file.write_all(format!("typedef enum {}_t {{\n", type_name).as_bytes())?;
+    for variant in e.iter() {
+        generate_doc(h_file, &variant.doc, 1)?;
+        h_file.write_all(format!("    {}_{},\n", type_name, variant.name).as_bytes())?;
+    }
+    h_file.write_all(format!("}} {};\n", type_name).as_bytes())?;
+    h_file.write_all(format!("#define {}{}_COUNT {}\n\n", prefix.macro_, name, e.len()).as_bytes())?;
+    Ok(())
+}
+
+/// Write the constants of the protocol as macros in the generated .h file
+fn generate_constants(h_file: &mut fs::File, p: &Protocol, prefix: &Prefix) -> Result<(), std::io::Error> {
+    let mut constants = p.ordered_constants().peekable();
+    if constants.peek().is_none() {
+        return Ok(());
+    }
+    for (name, constant) in constants {
+        generate_doc(h_file, &constant.doc, 0)?;
+        h_file.write_all(format!("#define {}{} {}\n", prefix.macro_, name, constant.value.to_c(&constant.type_, &prefix.function)).as_bytes())?;
+    }
+    h_file.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Where a value is stored in the generated C code
+/// Lists and bytes are stored as `name` and `name_len` in a struct field
+/// and as a struct with `data` and `len` members everywhere else
+#[derive(Clone)]
+enum Place {
+    Field(String),
+    Value(String),
+}
+
+impl Place {
+    /// Expression of the value itself
+    fn value(&self) -> &str {
+        match self {
+            Place::Field(e) | Place::Value(e) => e,
+        }
+    }
+
+    /// Expression of the data pointer of a list or bytes
+    fn data(&self) -> String {
+        match self {
+            Place::Field(e) => e.clone(),
+            Place::Value(e) => format!("{}.data", e),
+        }
+    }
+
+    /// Expression of the length of a list or bytes
+    fn len(&self) -> String {
+        match self {
+            Place::Field(e) => format!("{}_len", e),
+            Place::Value(e) => format!("{}.len", e),
+        }
+    }
+
+    /// Expression of the keys of a map
+    fn keys(&self) -> String {
+        match self {
+            Place::Field(e) => format!("{}_keys", e),
+            Place::Value(e) => format!("{}.keys", e),
+        }
+    }
+
+    /// Expression of the values of a map
+    fn values(&self) -> String {
+        match self {
+            Place::Field(e) => format!("{}_values", e),
+            Place::Value(e) => format!("{}.values", e),
+        }
+    }
+
+    /// Place of a value of a map
+    fn map_value(&self, index: &str) -> Place {
+        Place::Value(format!("{}[{}]", self.values(), index))
+    }
+
+    /// Place of an element of a list
+    fn element(&self, index: &str) -> Place {
+        Place::Value(format!("{}[{}]", self.data(), index))
+    }
+
+    /// Place of an element of a fixed size array
+    fn index(&self, index: &str) -> Place {
+        Place::Value(format!("{}[{}]", self.value(), index))
+    }
+
+    /// Place of the value pointed by an optional
+    fn pointee(&self) -> Place {
+        Place::Value(format!("{}[0]", self.value()))
+    }
+
+    /// Place of a member of a struct value
+    fn member(&self, member: &str) -> Place {
+        Place::Value(format!("{}.{}", self.value(), member))
+    }
+}
+
+/// Members of the structs of the Typst value types made of several values, with their type,
+/// they are encoded one after the other
+fn value_components(t: &Types) -> Vec<(String, Types)> {
+    match t {
+        Types::Length => vec![("abs".to_string(), Types::Float), ("em".to_string(), Types::Float)],
+        Types::Relative => {
+            let mut components = value_components(&Types::Length);
+            for (member, _) in &mut components {
+                *member = format!("length.{}", member);
+            }
+            components.push(("ratio".to_string(), Types::Float));
+            components
+        }
+        Types::Color(ColorSpace::Rgb) => ColorSpace::Rgb
+            .components()
+            .iter()
+            .map(|member| (member.to_string(), Types::Integer { bytes: 1, signed: false }))
+            .collect(),
+        Types::Color(space) => space.components().iter().map(|member| (member.to_string(), Types::Float)).collect(),
+        Types::Date => vec![
+            ("year".to_string(), Types::Integer { bytes: 4, signed: true }),
+            ("month".to_string(), Types::Integer { bytes: 1, signed: false }),
+            ("day".to_string(), Types::Integer { bytes: 1, signed: false }),
+        ],
+        Types::Datetime => {
+            let mut components = value_components(&Types::Date);
+            for member in ["hour", "minute", "second"] {
+                components.push((member.to_string(), Types::Integer { bytes: 1, signed: false }));
+            }
+            components
+        }
+        Types::TextRun => vec![
+            ("text".to_string(), Types::String),
+            ("bold".to_string(), Types::Bool),
+            ("italic".to_string(), Types::Bool),
+            ("color".to_string(), Types::Optional(Box::new(Types::Color(ColorSpace::Rgb)))),
+            ("size".to_string(), Types::Optional(Box::new(Types::Length))),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// Collect the Typst value types made of several values that a type uses, with the ones they are made of
+fn collect_value_types(t: &Types, used: &mut HashSet<String>) {
+    match t {
+        Types::Array(t) | Types::Map(t) | Types::Optional(t) | Types::FixedArray(t, _) | Types::Alias(_, t) => {
+            collect_value_types(t, used)
+        }
+        _ if !value_components(t).is_empty() && used.insert(t.to_c(true)) => {
+            // the members of a relative length are flattened but it stores a length struct
+            if let Types::Relative = t {
+                collect_value_types(&Types::Length, used);
+            }
+            for (_, member) in value_components(t) {
+                collect_value_types(&member, used);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Write the structs of the Typst value types used by the protocol, their names are prefixed like the functions
+fn generate_value_types(h_file: &mut fs::File, used: &HashSet<String>, prefix: &Prefix) -> Result<(), std::io::Error> {
+    if used.contains("length") {
+        h_file.write_all(b"// Typst length, `abs` is in points and `em` is relative to the font size\n")?;
+        h_file.write_all(format!("typedef struct {{\n    float abs;\n    float em;\n}} {}length;\n\n", prefix.function).as_bytes())?;
+    }
+    if used.contains("relative") {
+        h_file.write_all(b"// Typst relative length, a ratio of 1 is 100%\n")?;
+        h_file.write_all(format!("typedef struct {{\n    {}length length;\n    float ratio;\n}} {}relative;\n\n", prefix.function, prefix.function).as_bytes())?;
+    }
+    let colors = ColorSpace::ALL.map(|space| (format!("Typst color in the {} color space", space.name()), Types::Color(space)));
+    let dates = [
+        ("Typst datetime with only a date".to_string(), Types::Date),
+        ("Typst datetime, a date and a time".to_string(), Types::Datetime),
+        ("Styled text, the color and size are NULL to keep the current ones".to_string(), Types::TextRun),
+    ];
+    // text runs use the color and length structs so they come after them
+    for (doc, t) in colors.into_iter().chain(dates).filter(|(_, t)| used.contains(&t.to_c(true))) {
+        h_file.write_all(format!("// {}\ntypedef struct {{\n", doc).as_bytes())?;
+        for (member, member_type) in value_components(&t) {
+            h_file.write_all(format!("    {};\n", c_declaration(&member_type, &member, prefix)).as_bytes())?;
+        }
+        h_file.write_all(format!("}} {};\n\n", c_type_name(&t, prefix)).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Indentation of generated code at the given level
+fn indent(level: usize) -> String {
+    "    ".repeat(level)
+}
+
+/// Name of a type when used to build the name of a generated type
+fn mangle(t: &Types) -> String {
+    match t {
+        Types::String => "string".to_string(),
+        Types::Bytes => "bytes".to_string(),
+        Types::Array(t) => format!("{}_list", mangle(t)),
+        Types::Optional(t) => format!("{}_optional", mangle(t)),
+        Types::FixedArray(t, size) => format!("{}_array{}", mangle(t), size),
+        Types::Map(t) => format!("{}_map", mangle(t)),
+        _ => t.to_c(false),
+    }
+}
+
+/// C name of a type, the declared types and the structs of lists, maps, bytes and Typst values are prefixed
+/// like the functions so the headers of several packages can be included together
+fn c_type_name(t: &Types, prefix: &Prefix) -> String {
+    match t {
+        Types::Struct(name) => format!("struct {}_t", prefix.type_name(name)),
+        Types::Enum(name) | Types::Alias(name, _) => prefix.type_name(name),
+        Types::Array(_) | Types::Map(_) | Types::Bytes => format!("{}{}", prefix.function, mangle(t)),
+        _ if !value_components(t).is_empty() => format!("{}{}", prefix.function, t.to_c(true)),
+        _ => t.to_c(true),
+    }
+}
+
+/// C declaration of a standalone value, lists are stored in a generated struct
+fn c_declaration(t: &Types, declarator: &str, prefix: &Prefix) -> String {
+    match t {
+        Types::Optional(t) => c_declaration(t, &format!("*{}", declarator), prefix),
+        Types::FixedArray(t, size) => {
+            if declarator.starts_with('*') {
+                c_declaration(t, &format!("({})[{}]", declarator, size), prefix)
+            } else {
+                c_declaration(t, &format!("{}[{}]", declarator, size), prefix)
+            }
+        }
+        _ => format!("{} {}", c_type_name(t, prefix), declarator),
+    }
+}
+
+/// Check if a field of this type is stored along with a `_len` field
+fn has_len_field(t: &Types) -> bool {
+    matches!(t.resolved(), Types::Array(_) | Types::Bytes | Types::Map(_))
+}
+
+/// Write the declaration of a struct field, lists, maps and bytes are followed by their length
+/// The type of the field can be replaced with `@c_type`
+fn generate_field_declaration(
+    h_file: &mut fs::File,
+    level: usize,
+    field: &Field,
+    prefix: &Prefix,
+) -> Result<(), std::io::Error> {
+    let tab = indent(level);
+    let (name, t) = (field.name, &field.type_);
+    if let Some(c_type) = field.attribute("c_type").and_then(|attribute| attribute.string()) {
+        h_file.write_all(format!("{}{} {};\n", tab, c_type, name).as_bytes())?;
+        return Ok(());
+    }
+    // aliases of lists, maps and bytes are stored like them, with their length
+    let t = if has_len_field(t) { t.resolved() } else { t };
+    match t {
+        Types::Array(t) => {
+            h_file.write_all(format!("{}{};\n", tab, c_declaration(t, &format!("*{}", name), prefix)).as_bytes())?;
+        }
+        Types::Bytes => {
+            h_file.write_all(format!("{}uint8_t *{};\n", tab, name).as_bytes())?;
+        }
+        Types::Map(t) => {
+            h_file.write_all(format!("{}char **{}_keys;\n", tab, name).as_bytes())?;
+            h_file.write_all(format!("{}{};\n", tab, c_declaration(t, &format!("*{}_values", name), prefix)).as_bytes())?;
+        }
+        _ => {
+            h_file.write_all(format!("{}{};\n", tab, c_declaration(t, name, prefix)).as_bytes())?;
+        }
+    }
+    if has_len_field(t) {
+        h_file.write_all(format!("{}size_t {}_len;\n", tab, name).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Collect the lists and maps that need a generated struct and the aliases that need a typedef, inner ones first
+/// Lists and maps that are directly a field are stored in the struct itself
+fn collect_container_types(t: &Types, field: bool, containers: &mut Vec<Types>) {
+    match t {
+        Types::Alias(_, target) => {
+            collect_container_types(target, false, containers);
+            containers.push(t.clone());
+        }
+        Types::Array(element) | Types::Map(element) => {
+            collect_container_types(element, false, containers);
+            if !field {
+                containers.push(t.clone());
+            }
+        }
+        Types::Optional(t) | Types::FixedArray(t, _) => collect_container_types(t, false, containers),
+        _ => {}
+    }
+}
+
+/// Write the struct used to store a standalone list or map, or the typedef of an alias
+fn generate_container_type(h_file: &mut fs::File, t: &Types, p: &Protocol, prefix: &Prefix) -> Result<(), std::io::Error> {
+    if let Types::Alias(alias, target) = t {
+        generate_doc(h_file, &p.get_alias(alias).doc, 0)?;
+        h_file.write_all(format!("typedef {};\n", c_declaration(target, &prefix.type_name(alias), prefix)).as_bytes())?;
+        return Ok(());
+    }
+    let name = c_type_name(t, prefix);
+    h_file.write_all(format!("typedef struct {}_t {{\n", name).as_bytes())?;
+    match t {
+        Types::Array(element) => {
+            h_file.write_all(format!("    {};\n", c_declaration(element, "*data", prefix)).as_bytes())?;
+        }
+        Types::Map(value) => {
+            h_file.write_all(b"    char **keys;\n")?;
+            h_file.write_all(format!("    {};\n", c_declaration(value, "*values", prefix)).as_bytes())?;
+        }
+        _ => unreachable!("Only lists and maps are stored in a generated struct"),
+    }
+    h_file.write_all(b"    size_t len;\n")?;
+    h_file.write_all(format!("}} {};\n", name).as_bytes())?;
     Ok(())
 }
 
 /// Write a struct definition in the generated .h file
-fn generate_struct(h_file: &mut fs::File, name: &str, s: &Struct) -> Result<(), std::io::Error> {
-    h_file.write(format!("typedef struct {}_t {{\n", name).as_bytes())?;
+fn generate_struct(h_file: &mut fs::File, name: &str, s: &Struct, prefix: &Prefix) -> Result<(), std::io::Error> {
+    let name = prefix.type_name(name);
+    generate_doc(h_file, &s.doc, 0)?;
+    h_file.write_all(format!("typedef struct {}_t {{\n", name).as_bytes())?;
+    for field in s.iter() {
+        generate_doc(h_file, &field_doc(field), 1)?;
+        generate_field_declaration(h_file, 1, field, prefix)?;
+    }
+    h_file.write_all(b"} ")?;
+    h_file.write_all(format!("{};\n", name).as_bytes())?;
+    Ok(())
+}
+
+/// Write a union definition in the generated .h file
+/// The kind enum tells which field of the anonymous union is set
+fn generate_union(h_file: &mut fs::File, name: &str, s: &Struct, prefix: &Prefix) -> Result<(), std::io::Error> {
+    let name = prefix.type_name(name);
+    h_file.write_all(format!("typedef enum {}_kind_t {{\n", name).as_bytes())?;
+    for field in s.iter() {
+        generate_doc(h_file, &field.doc, 1)?;
+        h_file.write_all(format!("    {}_{},\n", name, field.name).as_bytes())?;
+    }
+    h_file.write_all(format!("}} {}_kind;\n", name).as_bytes())?;
+    generate_doc(h_file, &s.doc, 0)?;
+    h_file.write_all(format!("typedef struct {}_t {{\n", name).as_bytes())?;
+    h_file.write_all(format!("    {}_kind kind;\n", name).as_bytes())?;
+    h_file.write_all(b"    union {\n")?;
     for field in s.iter() {
-        h_file.write(format!("    {} {};\n", field.1.to_c(true), field.0).as_bytes())?;
-		if let Types::Array(_) = field.1 {
-            h_file.write(format!("    size_t {}_len;\n", field.0).as_bytes())?;
+        generate_doc(h_file, &field_doc(field), 2)?;
+		if has_len_field(&field.type_) {
+            h_file.write_all(b"        struct {\n")?;
+            generate_field_declaration(h_file, 3, field, prefix)?;
+            h_file.write_all(b"        };\n")?;
+        } else {
+            generate_field_declaration(h_file, 2, field, prefix)?;
         }
     }
-    h_file.write(b"} ")?;
-    h_file.write(format!("{};\n", name).as_bytes())?;
+    h_file.write_all(b"    };\n")?;
+    h_file.write_all(format!("}} {};\n", name).as_bytes())?;
+    Ok(())
+}
+
+/// Write a switch over the kind of a union, the content of each case is written by `case_body`
+fn generate_union_switch<F>(
+	file: &mut fs::File,
+	name: &str,
+	s: &Struct,
+	kind: &str,
+	prefix: &Prefix,
+	mut case_body: F,
+) -> Result<(), std::io::Error>
+where
+	F: FnMut(&mut fs::File, &Field) -> Result<(), std::io::Error>,
+{
+	file.write_all(format!("    switch ({}) {{\n", kind).as_bytes())?;
+	for field in s.iter() {
+		file.write_all(format!("    case {}_{}: {{\n", prefix.type_name(name), field.name).as_bytes())?;
+		case_body(file, field)?;
+		file.write_all(b"        break;\n    }\n")?;
+	}
+	file.write_all(b"    }\n")?;
+	Ok(())
+}
+
+/// Write the macros holding the default values of the fields of a struct
+fn generate_defaults(h_file: &mut fs::File, name: &str, s: &Struct, prefix: &Prefix) -> Result<(), std::io::Error> {
+    for field in s.iter() {
+        if let Some(default) = &field.default {
+            h_file.write_all(format!("#define {}{}_{}_DEFAULT {}\n", prefix.macro_, name, field.name, default.to_c(&field.type_, &prefix.function)).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a struct init function signature
+fn generate_struct_init_signature(file: &mut fs::File, name: &str, prefix: &Prefix) -> Result<(), std::io::Error> {
+    file.write_all(format!("int {}init_{}({} *s)", prefix.function, name, prefix.type_name(name)).as_bytes())?;
+    Ok(())
+}
+
+/// Check if a value of this type contains a struct that must be initialized
+fn need_init(t: &Types) -> bool {
+    match t {
+        Types::Struct(_) => true,
+        Types::FixedArray(t, _) | Types::Alias(_, t) => need_init(t),
+        _ => false,
+    }
+}
+
+/// Write the code that initializes the structs stored in a value
+fn generate_init(c_file: &mut fs::File, t: &Types, place: &Place, level: usize, prefix: &Prefix) -> Result<(), std::io::Error> {
+    let tab = indent(level);
+    match t {
+        Types::Struct(name) => {
+            c_file.write_all(format!("{}if ((err = {}init_{}(&{}))) {{\n", tab, prefix.function, name, place.value()).as_bytes())?;
+            c_file.write_all(format!("{}    return err;\n{}}}\n", tab, tab).as_bytes())?;
+        }
+        Types::FixedArray(t, size) => {
+            let i = format!("i{}", level);
+            c_file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, size, i).as_bytes())?;
+            generate_init(c_file, t, &place.index(&i), level + 1, prefix)?;
+            c_file.write_all(format!("{}}}\n", tab).as_bytes())?;
+        }
+        Types::Alias(_, t) => generate_init(c_file, t, place, level, prefix)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Write the code that sets a zeroed field of the struct `name` to its default value
+fn generate_field_init(c_file: &mut fs::File, name: &str, field: &Field, place: &Place, level: usize, prefix: &Prefix) -> Result<(), std::io::Error> {
+    let tab = indent(level);
+    match (field.type_.resolved(), &field.default) {
+        (Types::String, Some(_)) => {
+            let default = format!("{}{}_{}_DEFAULT", prefix.macro_, name, field.name);
+            c_file.write_all(format!("{}{} = malloc(sizeof({}));\n", tab, place.value(), default).as_bytes())?;
+            c_file.write_all(format!("{}if (!{}) {{\n{}    return 1;\n{}}}\n", tab, place.value(), tab, tab).as_bytes())?;
+            c_file.write_all(format!("{}strcpy({}, {});\n", tab, place.value(), default).as_bytes())?;
+        }
+        (_, Some(_)) => {
+            c_file.write_all(format!("{}{} = {}{}_{}_DEFAULT;\n", tab, place.value(), prefix.macro_, name, field.name).as_bytes())?;
+        }
+        (t, None) if need_init(t) => generate_init(c_file, t, place, level, prefix)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Write a struct init function in the generated .c file
+/// Fields are set to their default value or zero, unions are set to zero
+fn generate_struct_init(c_file: &mut fs::File, name: &str, s: &Struct, prefix: &Prefix) -> Result<(), std::io::Error> {
+    generate_struct_init_signature(c_file, name, prefix)?;
+    c_file.write_all(b" {\n")?;
+    c_file.write_all(b"    int err;\n    (void)err;\n")?;
+    c_file.write_all(b"    memset(s, 0, sizeof(*s));\n")?;
+    if !matches!(s.get_type(), StructType::Union) {
+        for field in s.iter() {
+            generate_field_init(c_file, name, field, &Place::Field(format!("s->{}", field.name)), 1, prefix)?;
+        }
+    }
+    c_file.write_all(b"    return 0;\n}\n")?;
     Ok(())
 }
 
@@ -225,57 +807,78 @@ fn generate_struct(h_file: &mut fs::File, name: &str, s: &Struct) -> Result<(),
 fn generate_struct_free_signature(
 	file: &mut fs::File,
 	name: &str,
+	prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
-	file.write(format!("void free_{}({} *s)", name, name).as_bytes())?;
+	file.write_all(format!("void {}free_{}({} *s)", prefix.function, name, prefix.type_name(name)).as_bytes())?;
 	Ok(())
 }
 
 /// Check if a type needs to be freed
 fn need_free(t: &Types) -> bool {
-	match t {
-		Types::String => true,
-		Types::Struct(_) => true,
-		Types::Array(t) => need_free(t.as_ref()),
-		Types::Optional(t) => need_free(t.as_ref()),
-		_ => false,
-	}
+	let t = t.resolved();
+	matches!(
+		t,
+		Types::String | Types::Bytes | Types::Struct(_) | Types::Array(_) | Types::Optional(_) | Types::Map(_) | Types::TextRun
+	) || matches!(t, Types::FixedArray(t, _) if need_free(t))
 }
 
-/// Write a struct free function body in the generated .c file
-fn generate_struct_field_free_body(
+/// Write the code that frees a value in the generated .c file
+fn generate_free(
 	c_file: &mut fs::File,
-	field_name: &str,
 	t: &Types,
+	place: &Place,
+	level: usize,
+	prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
+	let tab = indent(level);
 	match t {
 		Types::String => {
-			c_file.write(format!("    if (s->{}) {{\n", field_name).as_bytes())?;
-			c_file.write(format!("        free(s->{});\n", field_name).as_bytes())?;
-			c_file.write(b"    }\n")?;
+			c_file.write_all(format!("{}free({});\n", tab, place.value()).as_bytes())?;
 		}
-		Types::Struct(_) => {
-			c_file.write(format!("    free_{}(&s->{});\n", t.to_c(false), field_name).as_bytes())?;
+		Types::Bytes => {
+			c_file.write_all(format!("{}free({});\n", tab, place.data()).as_bytes())?;
+		}
+		Types::Struct(name) => {
+			c_file.write_all(format!("{}{}free_{}(&{});\n", tab, prefix.function, name, place.value()).as_bytes())?;
 		}
 		Types::Array(t) => {
 			if need_free(t.as_ref()) {
-				c_file.write(format!("    for (size_t i = 0; i < s->{}_len; i++) {{\n", field_name).as_bytes())?;
-				generate_struct_field_free_body(c_file, &format!("{}[i]", field_name), t.as_ref())?;
-				c_file.write(b"    }\n")?;
+				let i = format!("i{}", level);
+				c_file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, place.len(), i).as_bytes())?;
+				generate_free(c_file, t, &place.element(&i), level + 1, prefix)?;
+				c_file.write_all(format!("{}}}\n", tab).as_bytes())?;
 			}
-			c_file.write(format!("    free(s->{});\n", field_name).as_bytes())?;
+			c_file.write_all(format!("{}free({});\n", tab, place.data()).as_bytes())?;
+		}
+		Types::Map(t) => {
+			let i = format!("i{}", level);
+			c_file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, place.len(), i).as_bytes())?;
+			c_file.write_all(format!("{}    free({}[{}]);\n", tab, place.keys(), i).as_bytes())?;
+			generate_free(c_file, t, &place.map_value(&i), level + 1, prefix)?;
+			c_file.write_all(format!("{}}}\n", tab).as_bytes())?;
+			c_file.write_all(format!("{}free({});\n", tab, place.keys()).as_bytes())?;
+			c_file.write_all(format!("{}free({});\n", tab, place.values()).as_bytes())?;
+		}
+		Types::FixedArray(t, size) if need_free(t.as_ref()) => {
+			let i = format!("i{}", level);
+			c_file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, size, i).as_bytes())?;
+			generate_free(c_file, t, &place.index(&i), level + 1, prefix)?;
+			c_file.write_all(format!("{}}}\n", tab).as_bytes())?;
 		}
         Types::Optional(t) => {
+            c_file.write_all(format!("{}if ({}) {{\n", tab, place.value()).as_bytes())?;
             if need_free(t.as_ref()) {
-                c_file.write(format!("    if (s->{}) {{\n", field_name).as_bytes())?;
-                generate_struct_field_free_body(c_file, &format!("{}[0]", field_name), t.as_ref())?;
-                c_file.write(b"        free(s->")?; c_file.write(field_name.as_bytes())?; c_file.write(b");\n")?;
-                c_file.write(b"    }\n")?;
-            } else {
-                c_file.write(format!("    if (s->{}) {{\n", field_name).as_bytes())?;
-                c_file.write(format!("        free(s->{});\n", field_name).as_bytes())?;
-                c_file.write(b"    }\n")?;
+                generate_free(c_file, t, &place.pointee(), level + 1, prefix)?;
             }
+            c_file.write_all(format!("{}    free({});\n", tab, place.value()).as_bytes())?;
+            c_file.write_all(format!("{}}}\n", tab).as_bytes())?;
         }
+		Types::TextRun => {
+			for (member, t) in value_components(t) {
+				generate_free(c_file, &t, &place.member(&member), level, prefix)?;
+			}
+		}
+		Types::Alias(_, t) => generate_free(c_file, t, place, level, prefix)?,
 		_ => {}
 	}
 	Ok(())
@@ -285,14 +888,21 @@ fn generate_struct_field_free_body(
 fn generate_struct_free(
 	c_file: &mut fs::File,
 	name: &str,
-	s: &Struct
+	s: &Struct,
+	prefix: &Prefix
 ) -> Result<(), std::io::Error> {
-	generate_struct_free_signature(c_file, name)?;
-	c_file.write(b" {\n")?;
-	for field in s.iter() {
-		generate_struct_field_free_body(c_file, &field.0, &field.1)?;
+	generate_struct_free_signature(c_file, name, prefix)?;
+	c_file.write_all(b" {\n")?;
+	if let StructType::Union = s.get_type() {
+		generate_union_switch(c_file, name, s, "s->kind", prefix, |c_file, field| {
+			generate_free(c_file, &field.type_, &Place::Field(format!("s->{}", field.name)), 2, prefix)
+		})?;
+	} else {
+		for field in s.iter() {
+			generate_free(c_file, &field.type_, &Place::Field(format!("s->{}", field.name)), 1, prefix)?;
+		}
 	}
-	c_file.write(b"}\n")?;
+	c_file.write_all(b"}\n")?;
 	Ok(())
 }
 
@@ -301,93 +911,257 @@ fn generate_struct_decode_signature(
     file: &mut fs::File,
     name: &str,
     s: &Struct,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
-    if let StructType::Struct = s.get_type() {
-        file.write(
+    if !s.is_protocol() {
+        file.write_all(
             format!(
-                "int decode_{}(uint8_t *__input_buffer, size_t buffer_len, {} *out, size_t *buffer_offset)",
-                name, name
+                "int {}decode_{}(uint8_t *__input_buffer, size_t buffer_len, {} *out, size_t *buffer_offset)",
+                prefix.function, name, prefix.type_name(name)
             )
             .as_bytes(),
         )?;
     } else {
-        file.write(format!("int decode_{}(size_t buffer_len, {} *out)", name, name).as_bytes())?;
+        file.write_all(format!("int {}decode_{}(size_t buffer_len, {} *out)", prefix.function, name, prefix.type_name(name)).as_bytes())?;
     }
     Ok(())
 }
 
-/// Write a line to decode a field in a struct
-fn generate_struct_decode_line(
+/// Write the code that decodes a value
+/// The length `constraints` of the field are checked as soon as the length of its list, map or bytes is decoded
+fn generate_decode(
 	file: &mut fs::File,
-	field_name: &str,
 	t: &Types,
+	place: &Place,
+	level: usize,
+	constraints: &[Constraint],
+	prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
+	let tab = indent(level);
+	let value = place.value();
 	match t {
 		Types::Int => {
-			file.write(format!("    NEXT_INT(out->{})\n", field_name).as_bytes())?;
+			file.write_all(format!("{}{}NEXT_INT({})\n", tab, prefix.macro_, value).as_bytes())?;
 		}
-		Types::Float | Types::Point => {
-			file.write(format!("    NEXT_FLOAT(out->{})\n", field_name).as_bytes())?;
+		Types::Integer { bytes, .. } => {
+			file.write_all(format!("{}{}NEXT_SIZED_INT({}, {})\n", tab, prefix.macro_, value, bytes).as_bytes())?;
+		}
+		Types::Float | Types::Point | Types::Angle | Types::Ratio | Types::Fraction => {
+			file.write_all(format!("{}{}NEXT_FLOAT({})\n", tab, prefix.macro_, value).as_bytes())?;
+		}
+		Types::Length | Types::Relative | Types::Color(_) | Types::Date | Types::Datetime | Types::TextRun => {
+			for (member, t) in value_components(t) {
+				generate_decode(file, &t, &place.member(&member), level, &[], prefix)?;
+			}
+		}
+		Types::Duration => generate_decode(file, &Types::Integer { bytes: 8, signed: true }, place, level, &[], prefix)?,
+		Types::Double => {
+			file.write_all(format!("{}{}NEXT_DOUBLE({})\n", tab, prefix.macro_, value).as_bytes())?;
 		}
 		Types::String => {
-			file.write(format!("    NEXT_STR(out->{})\n", field_name).as_bytes())?;
+			file.write_all(format!("{}{}NEXT_STR({})\n", tab, prefix.macro_, value).as_bytes())?;
+		}
+		Types::Bytes => {
+			file.write_all(format!("{}{}NEXT_INT({})\n", tab, prefix.macro_, place.len()).as_bytes())?;
+			generate_length_check(file, constraints, &place.len(), level)?;
+			file.write_all(format!("{}{}NEXT_BYTES({}, {})\n", tab, prefix.macro_, place.data(), place.len()).as_bytes())?;
 		}
 		Types::Bool | Types::Char => {
-			file.write(format!("    NEXT_CHAR(out->{})\n", field_name).as_bytes())?;
+			file.write_all(format!("{}{}NEXT_CHAR({})\n", tab, prefix.macro_, value).as_bytes())?;
+		}
+		Types::Enum(name) => {
+			file.write_all(format!("{}{}NEXT_ENUM({}, {}{}_COUNT)\n", tab, prefix.macro_, value, prefix.macro_, name).as_bytes())?;
 		}
 		Types::Struct(name) => {
-			file.write(format!("    if ((err = decode_{}(__input_buffer + __buffer_offset, buffer_len - __buffer_offset, &out->{}, &__buffer_offset))){{return err;}}\n", name, field_name).as_bytes())?;
+			file.write_all(format!("{}if ((err = {}decode_{}(__input_buffer + __buffer_offset, buffer_len - __buffer_offset, &{}, &__buffer_offset))) {{\n", tab, prefix.function, name, value).as_bytes())?;
+			file.write_all(format!("{}    return err;\n{}}}\n", tab, tab).as_bytes())?;
 		}
 		Types::Array(t) => {
-			file.write(format!("    NEXT_INT(out->{}_len)\n", field_name).as_bytes())?;
-			file.write(format!("    if (out->{}_len == 0) {{\n        out->{} = NULL;\n    }} else {{\n", field_name, field_name).as_bytes())?;
-			file.write(format!("        out->{} = malloc(out->{}_len * sizeof({}));\n", field_name, field_name, t.to_c(false)).as_bytes())?;
-			file.write(format!("        if (!out->{}){{\n            return 1;\n        }}\n", field_name).as_bytes())?;
-			file.write(format!("        for (size_t i = 0; i < out->{}_len; i++) {{\n", field_name).as_bytes())?;
-			generate_struct_decode_line(file, &format!("{}[i]", field_name), t)?;
-			file.write(b"        }\n")?;
-			file.write(b"    }\n")?;
+			let (data, len) = (place.data(), place.len());
+			let i = format!("i{}", level);
+			file.write_all(format!("{}{}NEXT_INT({})\n", tab, prefix.macro_, len).as_bytes())?;
+			generate_length_check(file, constraints, &len, level)?;
+			file.write_all(format!("{}if ({} == 0) {{\n{}    {} = NULL;\n{}}} else {{\n", tab, len, tab, data, tab).as_bytes())?;
+			file.write_all(format!("{}    {} = malloc({} * sizeof(*{}));\n", tab, data, len, data).as_bytes())?;
+			file.write_all(format!("{}    if (!{}) {{\n{}        return 1;\n{}    }}\n", tab, data, tab, tab).as_bytes())?;
+			file.write_all(format!("{}    for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, len, i).as_bytes())?;
+			generate_decode(file, t, &place.element(&i), level + 2, &[], prefix)?;
+			file.write_all(format!("{}    }}\n{}}}\n", tab, tab).as_bytes())?;
+		}
+		Types::Map(t) => {
+			let (keys, values, len) = (place.keys(), place.values(), place.len());
+			let i = format!("i{}", level);
+			file.write_all(format!("{}{}NEXT_INT({})\n", tab, prefix.macro_, len).as_bytes())?;
+			generate_length_check(file, constraints, &len, level)?;
+			file.write_all(format!("{}if ({} == 0) {{\n{}    {} = NULL;\n{}    {} = NULL;\n{}}} else {{\n", tab, len, tab, keys, tab, values, tab).as_bytes())?;
+			file.write_all(format!("{}    {} = malloc({} * sizeof(*{}));\n", tab, keys, len, keys).as_bytes())?;
+			file.write_all(format!("{}    {} = malloc({} * sizeof(*{}));\n", tab, values, len, values).as_bytes())?;
+			file.write_all(format!("{}    if (!{} || !{}) {{\n{}        return 1;\n{}    }}\n", tab, keys, values, tab, tab).as_bytes())?;
+			file.write_all(format!("{}    for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, len, i).as_bytes())?;
+			file.write_all(format!("{}        {}NEXT_STR({}[{}])\n", tab, prefix.macro_, keys, i).as_bytes())?;
+			generate_decode(file, t, &place.map_value(&i), level + 2, &[], prefix)?;
+			file.write_all(format!("{}    }}\n{}}}\n", tab, tab).as_bytes())?;
+		}
+		Types::FixedArray(t, size) => {
+			let i = format!("i{}", level);
+			file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, size, i).as_bytes())?;
+			generate_decode(file, t, &place.index(&i), level + 1, &[], prefix)?;
+			file.write_all(format!("{}}}\n", tab).as_bytes())?;
 		}
         Types::Optional(t) => {
-            file.write(format!("    bool has_{};\n", field_name).as_bytes())?;
-            file.write(format!("    NEXT_CHAR(has_{})\n", field_name).as_bytes())?;
-            file.write(format!("    if (has_{}) {{\n", field_name).as_bytes())?;
-            file.write(format!("        out->{} = malloc(sizeof({}));\n", field_name, t.to_c(false)).as_bytes())?;
-            generate_struct_decode_line(file, &format!("{}[0]", field_name), t)?;
-            file.write(b"    } else {\n")?;
-            file.write(format!("        out->{} = NULL;\n", field_name).as_bytes())?;
-            file.write(b"    }\n")?;
+            let has_value = format!("has_value{}", level);
+            file.write_all(format!("{}{{\n{}    bool {};\n", tab, tab, has_value).as_bytes())?;
+            file.write_all(format!("{}    {}NEXT_CHAR({})\n", tab, prefix.macro_, has_value).as_bytes())?;
+            file.write_all(format!("{}    if ({}) {{\n", tab, has_value).as_bytes())?;
+            file.write_all(format!("{}        {} = malloc(sizeof(*{}));\n", tab, value, value).as_bytes())?;
+            file.write_all(format!("{}        if (!{}) {{\n{}            return 1;\n{}        }}\n", tab, value, tab, tab).as_bytes())?;
+            generate_decode(file, t, &place.pointee(), level + 2, constraints, prefix)?;
+            file.write_all(format!("{}    }} else {{\n", tab).as_bytes())?;
+            file.write_all(format!("{}        {} = NULL;\n", tab, value).as_bytes())?;
+            file.write_all(format!("{}    }}\n{}}}\n", tab, tab).as_bytes())?;
         }
+		Types::Alias(_, t) => generate_decode(file, t, place, level, constraints, prefix)?,
 	}
 	Ok(())
 }
 
+/// Write the code that checks the decoded length of a list, map or bytes, before it is allocated
+/// The decoder returns 4 if it isn't allowed by one of the length constraints
+fn generate_length_check(file: &mut fs::File, constraints: &[Constraint], len: &str, level: usize) -> Result<(), std::io::Error> {
+    let tab = indent(level);
+    for constraint in constraints {
+        if let ConstraintKind::Length(min, max) = constraint.kind {
+            let mut checks = Vec::new();
+            if min > 0 {
+                checks.push(format!("{} < {}", len, min));
+            }
+            checks.push(format!("{} > {}", len, max));
+            file.write_all(format!("{}if ({}) {{\n{}    return 4;\n{}}}\n", tab, checks.join(" || "), tab, tab).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the code that checks the constraints of a decoded field, the decoder returns 4 if one is not respected
+fn generate_constraints(file: &mut fs::File, field: &Field, place: &Place, level: usize, prefix: &Prefix) -> Result<(), std::io::Error> {
+    let tab = indent(level);
+    // optional values are only checked when they are set
+    let mut conditions = Vec::new();
+    let mut place = place.clone();
+    let mut t = field.type_.resolved();
+    while let Types::Optional(inner) = t {
+        conditions.push(place.value().to_string());
+        place = place.pointee();
+        t = inner.resolved();
+    }
+    for constraint in &field.constraints {
+        let mut checks = Vec::new();
+        match &constraint.kind {
+            ConstraintKind::Range(min, max) => {
+                // an unsigned value can't be below 0
+                if !(matches!(t, Types::Integer { signed: false, .. }) && min.as_number() == Some(0.0)) {
+                    checks.push(format!("{} < {}", place.value(), min.to_c(t, &prefix.function)));
+                }
+                checks.push(format!("{} > {}", place.value(), max.to_c(t, &prefix.function)));
+            }
+            // the length of lists, maps and bytes is checked while decoding them
+            ConstraintKind::Length(..) if !matches!(t, Types::String) => continue,
+            ConstraintKind::Length(min, max) => {
+                let len = format!("strlen({})", place.value());
+                if *min > 0 {
+                    checks.push(format!("{} < {}", len, min));
+                }
+                checks.push(format!("{} > {}", len, max));
+            }
+        }
+        let check = if conditions.is_empty() {
+            checks.join(" || ")
+        } else {
+            format!("{} && ({})", conditions.join(" && "), checks.join(" || "))
+        };
+        file.write_all(format!("{}if ({}) {{\n{}    return 4;\n{}}}\n", tab, check, tab, tab).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Write the code that decodes a field added with `@since`,
+/// payloads of older versions end before it so it's set to its default value when the buffer is empty
+fn generate_missing_field(file: &mut fs::File, name: &str, field: &Field, place: &Place, prefix: &Prefix) -> Result<(), std::io::Error> {
+    file.write_all(b"    if (__buffer_offset < buffer_len) {\n")?;
+    generate_decode(file, &field.type_, place, 2, &field.constraints, prefix)?;
+    generate_constraints(file, field, place, 2, prefix)?;
+    file.write_all(b"    } else {\n")?;
+    match field.type_.resolved() {
+        Types::Array(_) | Types::Bytes => {
+            file.write_all(format!("        {} = NULL;\n        {} = 0;\n", place.data(), place.len()).as_bytes())?;
+        }
+        Types::Map(_) => {
+            file.write_all(format!("        {} = NULL;\n        {} = NULL;\n", place.keys(), place.values()).as_bytes())?;
+            file.write_all(format!("        {} = 0;\n", place.len()).as_bytes())?;
+        }
+        _ => {
+            file.write_all(format!("        memset(&{}, 0, sizeof({}));\n", place.value(), place.value()).as_bytes())?;
+        }
+    }
+    generate_field_init(file, name, field, place, 2, prefix)?;
+    file.write_all(b"    }\n")?;
+    Ok(())
+}
+
 /// Write a struct decode function body in the generated .c file
 fn generate_struct_decode_function(
     file: &mut fs::File,
     name: &str,
     s: &Struct,
     free_buffer: bool,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
-    generate_struct_decode_signature(file, name, s)?;
-    file.write(b" {\n")?;
-    if let StructType::Struct = s.get_type() {
-        file.write(b"    size_t __buffer_offset = 0;\n")?;
+    generate_struct_decode_signature(file, name, s, prefix)?;
+    file.write_all(b" {\n")?;
+    if !s.is_protocol() {
+        file.write_all(b"    size_t __buffer_offset = 0;\n")?;
     } else {
-        file.write(b"    INIT_BUFFER_UNPACK(buffer_len)\n")?;
-    }
-	file.write(b"    int err;\n    (void)err;\n")?;
-    for field in s.iter() {
-		generate_struct_decode_line(file, &field.0, &field.1)?;
+        file.write_all(format!("    {}INIT_BUFFER_UNPACK(buffer_len)\n", prefix.macro_).as_bytes())?;
     }
+	file.write_all(b"    int err;\n    (void)err;\n")?;
+	if let Some(version) = s.version {
+		file.write_all(b"    {\n        int version;\n")?;
+		file.write_all(format!("        {}NEXT_INT(version)\n", prefix.macro_).as_bytes())?;
+		// older versions are accepted when the fields they don't have were added with @since
+		match s.oldest_version() {
+			Some(oldest) if oldest < version => {
+				file.write_all(format!("        if (version < {} || version > {}) {{\n", oldest, version).as_bytes())?;
+			}
+			_ => file.write_all(format!("        if (version != {}) {{\n", version).as_bytes())?,
+		}
+		file.write_all(format!("            {}FREE_BUFFER()\n            return 5;\n        }}\n    }}\n", prefix.macro_).as_bytes())?;
+	}
+	if let StructType::Union = s.get_type() {
+		file.write_all(format!("    {}NEXT_ENUM(out->kind, {})\n", prefix.macro_, s.fields().len()).as_bytes())?;
+		generate_union_switch(file, name, s, "out->kind", prefix, |file, field| {
+			let place = Place::Field(format!("out->{}", field.name));
+			generate_decode(file, &field.type_, &place, 2, &field.constraints, prefix)?;
+			generate_constraints(file, field, &place, 2, prefix)
+		})?;
+	} else {
+		for field in s.iter() {
+			let place = Place::Field(format!("out->{}", field.name));
+			if field.since().is_some() {
+				generate_missing_field(file, name, field, &place, prefix)?;
+			} else {
+				generate_decode(file, &field.type_, &place, 1, &field.constraints, prefix)?;
+				generate_constraints(file, field, &place, 1, prefix)?;
+			}
+		}
+	}
     if free_buffer {
-        file.write(b"    FREE_BUFFER()\n")?;
+        file.write_all(format!("    {}FREE_BUFFER()\n", prefix.macro_).as_bytes())?;
     }
-	if let StructType::Struct = s.get_type() {
-		file.write(b"    *buffer_offset += __buffer_offset;\n")?;
+	if !s.is_protocol() {
+		file.write_all(b"    *buffer_offset += __buffer_offset;\n")?;
 	}
-    file.write(b"    return 0;\n")?;
-    file.write(b"}\n")?;
+    file.write_all(b"    return 0;\n")?;
+    file.write_all(b"}\n")?;
     Ok(())
 }
 
@@ -397,15 +1171,16 @@ fn generate_struct_decode(
     c_file: &mut fs::File,
     name: &str,
     s: &Struct,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
     let protocol = if let StructType::Protocol(_) = s.get_type() {
-        generate_struct_decode_signature(h_file, name, s)?;
-        h_file.write(b";\n")?;
+        generate_struct_decode_signature(h_file, name, s, prefix)?;
+        h_file.write_all(b";\n")?;
         true
     } else {
         false
     };
-    generate_struct_decode_function(c_file, name, s, protocol)?;
+    generate_struct_decode_function(c_file, name, s, protocol, prefix)?;
     Ok(())
 }
 
@@ -413,101 +1188,91 @@ fn generate_struct_decode(
 fn generate_size_function_signature(
     c_file: &mut fs::File,
     name: &str,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
-    c_file.write(format!("size_t {}_size(const void *s)", name).as_bytes())?;
+    c_file.write_all(format!("size_t {}{}_size(const void *value)", prefix.function, name).as_bytes())?;
     Ok(())
 }
 
-/// Write a line to calculate the size of a field in a struct
-fn generate_type_size(
+/// Encoded size of a type when it doesn't depend on the value
+fn fixed_size(t: &Types, prefix: &Prefix) -> Option<String> {
+    match t {
+        Types::Int | Types::Float | Types::Point | Types::Angle | Types::Ratio | Types::Fraction => Some(format!("{}TYPST_INT_SIZE", prefix.macro_)),
+        Types::Duration => Some("8".to_string()),
+        Types::Length | Types::Relative | Types::Color(_) | Types::Date | Types::Datetime | Types::TextRun => {
+            let sizes = value_components(t).iter().map(|(_, t)| fixed_size(t, prefix)).collect::<Option<Vec<_>>>()?;
+            Some(format!("({})", sizes.join(" + ")))
+        }
+        Types::Integer { bytes, .. } => Some(bytes.to_string()),
+        Types::Double => Some("8".to_string()),
+        Types::Bool | Types::Char | Types::Enum(_) => Some("1".to_string()),
+        Types::FixedArray(t, size) => fixed_size(t, prefix).map(|s| format!("{} * {}", size, s)),
+        Types::Alias(_, t) => fixed_size(t, prefix),
+        _ => None,
+    }
+}
+
+/// Write the code that adds the encoded size of a value to `size`
+fn generate_size(
     file: &mut fs::File,
-	name: &str,
     t: &Types,
-    field_name: &str,
+    place: &Place,
+    level: usize,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
+    let tab = indent(level);
+    if let Some(size) = fixed_size(t, prefix) {
+        file.write_all(format!("{}size += {};\n", tab, size).as_bytes())?;
+        return Ok(());
+    }
     match t {
-        Types::Int | Types::Float | Types::Point=> {
-            file.write(b"TYPST_INT_SIZE")?;
-        }
-        Types::Bool | Types::Char => {
-            file.write(b"1")?;
-        }
         Types::String => {
-            file.write(format!("string_size((({}*)s)->{})",name, field_name).as_bytes())?;
+            file.write_all(format!("{}size += {}string_size({});\n", tab, prefix.function, place.value()).as_bytes())?;
         }
-        Types::Struct(_) => {
-            file.write(format!("{}_size((void*)&(({}*)s)->{})", t.to_c(false), name, field_name).as_bytes())?;
+        Types::Bytes => {
+            file.write_all(format!("{}size += {}TYPST_INT_SIZE + {};\n", tab, prefix.macro_, place.len()).as_bytes())?;
+        }
+        Types::Struct(name) => {
+            file.write_all(format!("{}size += {}{}_size(&{});\n", tab, prefix.function, name, place.value()).as_bytes())?;
         }
         Types::Array(t) => {
-			if let Types::String = t.as_ref() {
-				file.write(
-					format!(
-						"TYPST_INT_SIZE + string_list_size((({}*)s)->{}, (({}*)s)->{}_len)",
-						name, field_name, name, field_name
-					)
-					.as_bytes(),
-				)?;
-			} else {
-				file.write(
-					format!(
-						"TYPST_INT_SIZE + list_size((({}*)s)->{}, (({}*)s)->{}_len, ",
-						name, field_name, name, field_name
-					)
-					.as_bytes(),
-				)?;
-				match t.as_ref() {
-					Types::Int | Types::Float | Types::Point => {
-						file.write(b"int_size")?;
-					}
-					Types::Bool | Types::Char => {
-						file.write(b"char_size")?;
-					}
-					Types::String => {
-						unreachable!("Array of strings are special cases");
-					}
-					Types::Struct(name) => {
-						file.write(format!("{}_size", name).as_bytes())?;
-					}
-					Types::Array(_) => {
-						unimplemented!("Array of arrays not supported");
-					}
-                    Types::Optional(_) => {
-                        unreachable!("Array of optionals not supported");
-                    }
-				}
-				file.write(format!(", sizeof(*(({}*)s)->{}))", name, field_name).as_bytes())?;
-			}
+            file.write_all(format!("{}size += {}TYPST_INT_SIZE;\n", tab, prefix.macro_).as_bytes())?;
+            if let Some(size) = fixed_size(t, prefix) {
+                file.write_all(format!("{}size += {} * {};\n", tab, place.len(), size).as_bytes())?;
+            } else {
+                let i = format!("i{}", level);
+                file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, place.len(), i).as_bytes())?;
+                generate_size(file, t, &place.element(&i), level + 1, prefix)?;
+                file.write_all(format!("{}}}\n", tab).as_bytes())?;
+            }
+        }
+        Types::Map(t) => {
+            let i = format!("i{}", level);
+            file.write_all(format!("{}size += {}TYPST_INT_SIZE;\n", tab, prefix.macro_).as_bytes())?;
+            file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, place.len(), i).as_bytes())?;
+            file.write_all(format!("{}    size += {}string_size({}[{}]);\n", tab, prefix.function, place.keys(), i).as_bytes())?;
+            generate_size(file, t, &place.map_value(&i), level + 1, prefix)?;
+            file.write_all(format!("{}}}\n", tab).as_bytes())?;
+        }
+        Types::FixedArray(t, size) => {
+            let i = format!("i{}", level);
+            file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, size, i).as_bytes())?;
+            generate_size(file, t, &place.index(&i), level + 1, prefix)?;
+            file.write_all(format!("{}}}\n", tab).as_bytes())?;
         }
         Types::Optional(t) => {
-            file.write(
-                format!(
-                    "optional_size((({}*)s)->{}, ",
-                    name, field_name
-                )
-                .as_bytes(),
-            )?;
-            match t.as_ref() {
-                Types::Int | Types::Float | Types::Point => {
-                    file.write(b"int_size")?;
-                }
-                Types::Bool | Types::Char => {
-                    file.write(b"char_size")?;
-                }
-                Types::String => {
-                    file.write(b"string_size")?;
-                }
-                Types::Struct(name) => {
-                    file.write(format!("{}_size", name).as_bytes())?;
-                }
-                Types::Array(_) => {
-                    unimplemented!("Optional of arrays not supported");
-                }
-                Types::Optional(_) => {
-                    unreachable!("Optional of optionals not supported");
-                }
+            file.write_all(format!("{}size += 1;\n", tab).as_bytes())?;
+            file.write_all(format!("{}if ({}) {{\n", tab, place.value()).as_bytes())?;
+            generate_size(file, t, &place.pointee(), level + 1, prefix)?;
+            file.write_all(format!("{}}}\n", tab).as_bytes())?;
+        }
+        Types::TextRun => {
+            for (member, t) in value_components(t) {
+                generate_size(file, &t, &place.member(&member), level, prefix)?;
             }
-            file.write(b")")?;
         }
+        Types::Alias(_, t) => generate_size(file, t, place, level, prefix)?,
+        _ => unreachable!("Fixed size types are handled above"),
     }
     Ok(())
 }
@@ -517,19 +1282,23 @@ fn generate_size_function(
     c_file: &mut fs::File,
     name: &str,
     s: &Struct,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
-    generate_size_function_signature(c_file, name)?;
-    c_file.write(b"{\n")?;
-    c_file.write(b"\treturn ")?;
-    let mut first = true;
-    for field in s.iter() {
-        if !first {
-            c_file.write(b" + ")?;
+    generate_size_function_signature(c_file, name, prefix)?;
+    c_file.write_all(b" {\n")?;
+    c_file.write_all(format!("    const {} *s = value;\n    (void)s;\n", prefix.type_name(name)).as_bytes())?;
+    c_file.write_all(b"    size_t size = 0;\n")?;
+    if let StructType::Union = s.get_type() {
+        c_file.write_all(b"    size += 1;\n")?;
+        generate_union_switch(c_file, name, s, "s->kind", prefix, |c_file, field| {
+            generate_size(c_file, &field.type_, &Place::Field(format!("s->{}", field.name)), 2, prefix)
+        })?;
+    } else {
+        for field in s.iter() {
+            generate_size(c_file, &field.type_, &Place::Field(format!("s->{}", field.name)), 1, prefix)?;
         }
-        first = false;
-        generate_type_size(c_file, name,  &field.1, &field.0)?;
     }
-    c_file.write(b";\n}\n")?;
+    c_file.write_all(b"    return size;\n}\n")?;
     Ok(())
 }
 
@@ -538,80 +1307,94 @@ fn generate_struct_encode_signature(
     file: &mut fs::File,
     name: &str,
     s: &Struct,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
-    if let StructType::Struct = s.get_type() {
-        file.write(
+    if !s.is_protocol() {
+        file.write_all(
             format!(
-                "int encode_{}(const {} *s, uint8_t *__input_buffer, size_t *buffer_len, size_t *buffer_offset)",
-                name, name
+                "int {}encode_{}(const {} *s, uint8_t *__input_buffer, size_t *buffer_len, size_t *buffer_offset)",
+                prefix.function, name, prefix.type_name(name)
             )
             .as_bytes(),
         )?;
     } else {
-        file.write(format!("int encode_{}(const {} *s)", name, name).as_bytes())?;
+        file.write_all(format!("int {}encode_{}(const {} *s)", prefix.function, name, prefix.type_name(name)).as_bytes())?;
     }
     Ok(())
 }
 
-/// Write a line to encode a field in a struct
-fn generate_struct_encode_function_encode_line(
+/// Write the code that encodes a value
+fn generate_encode(
     file: &mut fs::File,
-    field_name: &str,
     t: &Types,
+    place: &Place,
+    level: usize,
     is_struct: bool,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
+    let tab = indent(level);
+    let value = place.value();
     match t {
         Types::Int => {
-            file.write(format!("    INT_PACK(s->{})\n", field_name).as_bytes())?;
+            file.write_all(format!("{}{}INT_PACK({})\n", tab, prefix.macro_, value).as_bytes())?;
         }
-        Types::Float | Types::Point => {
-            file.write(format!("    FLOAT_PACK(s->{})\n", field_name).as_bytes())?;
+        Types::Integer { bytes, .. } => {
+            file.write_all(format!("{}{}SIZED_INT_PACK({}, {})\n", tab, prefix.macro_, value, bytes).as_bytes())?;
+        }
+        Types::Float | Types::Point | Types::Angle | Types::Ratio | Types::Fraction => {
+            file.write_all(format!("{}{}FLOAT_PACK({})\n", tab, prefix.macro_, value).as_bytes())?;
+        }
+        Types::Length | Types::Relative | Types::Color(_) | Types::Date | Types::Datetime | Types::TextRun => {
+            for (member, t) in value_components(t) {
+                generate_encode(file, &t, &place.member(&member), level, is_struct, prefix)?;
+            }
+        }
+        Types::Duration => generate_encode(file, &Types::Integer { bytes: 8, signed: true }, place, level, is_struct, prefix)?,
+        Types::Double => {
+            file.write_all(format!("{}{}DOUBLE_PACK({})\n", tab, prefix.macro_, value).as_bytes())?;
         }
         Types::String => {
-            file.write(format!("    STR_PACK(s->{})\n", field_name).as_bytes())?;
+            file.write_all(format!("{}{}STR_PACK({})\n", tab, prefix.macro_, value).as_bytes())?;
+        }
+        Types::Bytes => {
+            file.write_all(format!("{}{}BYTES_PACK({}, {})\n", tab, prefix.macro_, place.data(), place.len()).as_bytes())?;
         }
-        Types::Bool | Types::Char => {
-            file.write(format!("    CHAR_PACK(s->{})\n", field_name).as_bytes())?;
+        Types::Bool | Types::Char | Types::Enum(_) => {
+            file.write_all(format!("{}{}CHAR_PACK({})\n", tab, prefix.macro_, value).as_bytes())?;
         }
         Types::Struct(name) => {
-            file.write(format!("        if ((err = encode_{}(&s->{}, __input_buffer + __buffer_offset, {}buffer_len, &__buffer_offset))) {{\n", name, field_name, (if is_struct { "" } else { "&" })).as_bytes())?;
-            file.write(b"            return err;\n")?;
-            file.write(b"        }\n")?;
+            file.write_all(format!("{}if ((err = {}encode_{}(&{}, __input_buffer + __buffer_offset, {}buffer_len, &__buffer_offset))) {{\n", tab, prefix.function, name, value, (if is_struct { "" } else { "&" })).as_bytes())?;
+            file.write_all(format!("{}    return err;\n", tab).as_bytes())?;
+            file.write_all(format!("{}}}\n", tab).as_bytes())?;
         }
         Types::Array(t) => {
-            file.write(format!("    INT_PACK(s->{}_len)\n", field_name).as_bytes())?;
-            file.write(
-                format!("    for (size_t i = 0; i < s->{}_len; i++) {{\n", field_name).as_bytes(),
-            )?;
-            match t.as_ref() {
-                Types::Array(_) => {
-                    unreachable!("Array of arrays not supported");
-                }
-                Types::Optional(_) => {
-                    unreachable!("Array of optionals not supported");
-                }
-                _ => {
-                    generate_struct_encode_function_encode_line(
-                        file,
-                        &format!("{}[i]", field_name),
-                        t,
-                        is_struct,
-                    )?;
-                }
-            }
-			file.write(b"    }\n")?;
+            let i = format!("i{}", level);
+            file.write_all(format!("{}{}INT_PACK({})\n", tab, prefix.macro_, place.len()).as_bytes())?;
+            file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, place.len(), i).as_bytes())?;
+            generate_encode(file, t, &place.element(&i), level + 1, is_struct, prefix)?;
+			file.write_all(format!("{}}}\n", tab).as_bytes())?;
+        }
+        Types::Map(t) => {
+            let i = format!("i{}", level);
+            file.write_all(format!("{}{}INT_PACK({})\n", tab, prefix.macro_, place.len()).as_bytes())?;
+            file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, place.len(), i).as_bytes())?;
+            file.write_all(format!("{}    {}STR_PACK({}[{}])\n", tab, prefix.macro_, place.keys(), i).as_bytes())?;
+            generate_encode(file, t, &place.map_value(&i), level + 1, is_struct, prefix)?;
+            file.write_all(format!("{}}}\n", tab).as_bytes())?;
+        }
+        Types::FixedArray(t, size) => {
+            let i = format!("i{}", level);
+            file.write_all(format!("{}for (size_t {} = 0; {} < {}; {}++) {{\n", tab, i, i, size, i).as_bytes())?;
+            generate_encode(file, t, &place.index(&i), level + 1, is_struct, prefix)?;
+            file.write_all(format!("{}}}\n", tab).as_bytes())?;
         }
         Types::Optional(t) => {
-            file.write(format!("    CHAR_PACK(s->{} != NULL)\n", field_name).as_bytes())?;
-            file.write(format!("    if (s->{}) {{\n", field_name).as_bytes())?;
-            generate_struct_encode_function_encode_line(
-                file,
-                &format!("{}[0]", field_name),
-                t,
-                is_struct,
-            )?;
-            file.write(b"    }\n")?;
+            file.write_all(format!("{}{}CHAR_PACK({} != NULL)\n", tab, prefix.macro_, value).as_bytes())?;
+            file.write_all(format!("{}if ({}) {{\n", tab, value).as_bytes())?;
+            generate_encode(file, t, &place.pointee(), level + 1, is_struct, prefix)?;
+            file.write_all(format!("{}}}\n", tab).as_bytes())?;
         }
+        Types::Alias(_, t) => generate_encode(file, t, place, level, is_struct, prefix)?,
     }
     Ok(())
 }
@@ -621,41 +1404,55 @@ fn generate_struct_encode_function(
     file: &mut fs::File,
     name: &str,
     s: &Struct,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
-    generate_struct_encode_signature(file, name, s)?;
-    file.write(b" {\n")?;
-    if let StructType::Struct = s.get_type() {
-        file.write(b"    size_t __buffer_offset = 0;")?;
-        file.write(format!("    size_t s_size = {}_size(s);\n", name).as_bytes())?;
-        file.write(b"    if (s_size > *buffer_len) {\n")?;
-        file.write(b"        return 2;\n")?;
-        file.write(b"    }\n")?;
+    generate_struct_encode_signature(file, name, s, prefix)?;
+    file.write_all(b" {\n")?;
+    if !s.is_protocol() {
+        file.write_all(b"    size_t __buffer_offset = 0;")?;
+        file.write_all(format!("    size_t s_size = {}{}_size(s);\n", prefix.function, name).as_bytes())?;
+        file.write_all(b"    if (s_size > *buffer_len) {\n")?;
+        file.write_all(b"        return 2;\n")?;
+        file.write_all(b"    }\n")?;
     } else {
-        file.write(format!("    size_t buffer_len = {}_size(s);\n", name).as_bytes())?;
-        file.write(b"    INIT_BUFFER_PACK(buffer_len)\n")?;
+        // the version is sent before the fields
+        let version_size = match s.version {
+            Some(_) => format!(" + {}TYPST_INT_SIZE", prefix.macro_),
+            None => String::new(),
+        };
+        file.write_all(format!("    size_t buffer_len = {}{}_size(s){};\n", prefix.function, name, version_size).as_bytes())?;
+        file.write_all(format!("    {}INIT_BUFFER_PACK(buffer_len)\n", prefix.macro_).as_bytes())?;
+    }
+	file.write_all(b"    int err;\n	(void)err;\n")?;
+    if let Some(version) = s.version {
+        file.write_all(format!("    {}INT_PACK({})\n", prefix.macro_, version).as_bytes())?;
     }
-	file.write(b"    int err;\n	(void)err;\n")?;
 
-    for field in s.iter() {
-        generate_struct_encode_function_encode_line(
-            file,
-            &field.0,
-            &field.1,
-            if let StructType::Struct = s.get_type() {
-                true
-            } else {
-                false
-            },
-        )?;
+    if let StructType::Union = s.get_type() {
+        file.write_all(format!("    {}CHAR_PACK(s->kind)\n", prefix.macro_).as_bytes())?;
+        generate_union_switch(file, name, s, "s->kind", prefix, |file, field| {
+            generate_encode(file, &field.type_, &Place::Field(format!("s->{}", field.name)), 2, true, prefix)
+        })?;
+    } else {
+        for field in s.iter() {
+            generate_encode(
+                file,
+                &field.type_,
+                &Place::Field(format!("s->{}", field.name)),
+                1,
+                !s.is_protocol(),
+                prefix,
+            )?;
+        }
     }
-    if let StructType::Struct = s.get_type() {
-        file.write(b"\n    *buffer_offset += __buffer_offset;")?;
+    if !s.is_protocol() {
+        file.write_all(b"\n    *buffer_offset += __buffer_offset;")?;
     } else {
-        file.write(
+        file.write_all(
             b"\n    wasm_minimal_protocol_send_result_to_host(__input_buffer, buffer_len);",
         )?;
     }
-    file.write(b"\n    return 0;\n}\n")?;
+    file.write_all(b"\n    return 0;\n}\n")?;
     Ok(())
 }
 
@@ -666,35 +1463,72 @@ fn generate_struct_encode(
     c_file: &mut fs::File,
     name: &str,
     s: &Struct,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
     if let StructType::Protocol(_) = s.get_type() {
-        generate_struct_encode_signature(h_file, name, s)?;
-        h_file.write(b";\n")?;
+        generate_struct_encode_signature(h_file, name, s, prefix)?;
+        h_file.write_all(b";\n")?;
     }
 
-    generate_size_function(c_file, name, s)?;
-    generate_struct_encode_function(c_file, name, s)?;
+    generate_size_function(c_file, name, s, prefix)?;
+    generate_struct_encode_function(c_file, name, s, prefix)?;
+    Ok(())
+}
+
+/// Declare the decode, size and encode functions of a struct in the generated .c file
+fn generate_function_declarations(c_file: &mut fs::File, name: &str, s: &Struct, prefix: &Prefix) -> Result<(), std::io::Error> {
+    if s.decoder {
+        generate_struct_decode_signature(c_file, name, s, prefix)?;
+        c_file.write_all(b";\n")?;
+    }
+    if s.encoder {
+        generate_size_function_signature(c_file, name, prefix)?;
+        c_file.write_all(b";\n")?;
+        generate_struct_encode_signature(c_file, name, s, prefix)?;
+        c_file.write_all(b";\n")?;
+    }
     Ok(())
 }
 
 /// Generate all the necessary code for a struct in the generated .h and .c files
+/// The list and map structs needed by the struct are written before it if they were not already
 fn generate(
     h_file: &mut fs::File,
     c_file: &mut fs::File,
     name: &str,
     s: &Struct,
+    generated_containers: &mut HashSet<String>,
+    p: &Protocol,
+    prefix: &Prefix,
 ) -> Result<(), std::io::Error> {
-    generate_struct(h_file, name, s)?;
-	generate_struct_free_signature(h_file, name)?;
-	h_file.write(b";\n")?;
-	generate_struct_free(c_file, name, s)?;
+    let mut containers = Vec::new();
+    for field in s.iter() {
+        collect_container_types(&field.type_, true, &mut containers);
+    }
+    for t in containers {
+        if generated_containers.insert(mangle(&t)) {
+            generate_container_type(h_file, &t, p, prefix)?;
+        }
+    }
+    if let StructType::Union = s.get_type() {
+        generate_union(h_file, name, s, prefix)?;
+    } else {
+        generate_struct(h_file, name, s, prefix)?;
+    }
+    generate_defaults(h_file, name, s, prefix)?;
+    generate_struct_init_signature(h_file, name, prefix)?;
+    h_file.write_all(b";\n")?;
+    generate_struct_init(c_file, name, s, prefix)?;
+	generate_struct_free_signature(h_file, name, prefix)?;
+	h_file.write_all(b";\n")?;
+	generate_struct_free(c_file, name, s, prefix)?;
     if s.decoder {
-        generate_struct_decode(h_file, c_file, name, s)?;
+        generate_struct_decode(h_file, c_file, name, s, prefix)?;
     }
     if s.encoder {
-        generate_struct_encode(h_file, c_file, name, s)?;
+        generate_struct_encode(h_file, c_file, name, s, prefix)?;
     }
-    h_file.write(b"\n")?;
+    h_file.write_all(b"\n")?;
     Ok(())
 }
 
@@ -704,13 +1538,48 @@ pub fn generate_protocol(path: &str, p: &Protocol) -> Result<(), std::io::Error>
     let h_path = format!("{}/protocol.h", path);
     let mut c_file = fs::File::create(c_path)?;
     let mut h_file = fs::File::create(h_path)?;
-    generate_header(&mut h_file)?;
-    c_file.write(C.as_bytes())?;
+    let prefix = &Prefix::new(p.package());
+    generate_header(&mut h_file, prefix)?;
+    let mut value_types = HashSet::new();
+    for (_, s) in p.ordered_structs().chain(p.protocols()) {
+        for field in s.iter() {
+            collect_value_types(&field.type_, &mut value_types);
+        }
+    }
+    for (_, alias) in p.ordered_aliases() {
+        collect_value_types(&alias.type_, &mut value_types);
+    }
+    generate_value_types(&mut h_file, &value_types, prefix)?;
+    generate_constants(&mut h_file, p, prefix)?;
+    c_file.write_all(prefix.apply(C).as_bytes())?;
+    // structs can use each other through pointers so their functions are declared first
+    for (name, s) in p.ordered_structs() {
+        generate_function_declarations(&mut c_file, name, s, prefix)?;
+    }
+    for (name, e) in p.ordered_enums() {
+        generate_enum(&mut h_file, name, e, prefix)?;
+    }
+    let mut generated_containers = HashSet::new();
     for (name, s) in p.ordered_structs() {
-        generate(&mut h_file, &mut c_file, name, s)?;
+        generate(&mut h_file, &mut c_file, name, s, &mut generated_containers, p, prefix)?;
     }
     for (name, s) in p.protocols() {
-        generate(&mut h_file, &mut c_file, name, s)?;
+        generate(&mut h_file, &mut c_file, name, s, &mut generated_containers, p, prefix)?;
+    }
+    // aliases that are not used by a field are still part of the header
+    let mut containers = Vec::new();
+    for (name, alias) in p.ordered_aliases() {
+        collect_container_types(&Types::Alias(name.to_string(), Box::new(alias.type_.clone())), false, &mut containers);
+    }
+    let mut unused = false;
+    for t in containers {
+        if generated_containers.insert(mangle(&t)) {
+            generate_container_type(&mut h_file, &t, p, prefix)?;
+            unused = true;
+        }
+    }
+    if unused {
+        h_file.write_all(b"\n")?;
     }
     generate_footer(&mut h_file)?;
     Ok(())
@@ -1,9 +1,17 @@
-use std::{fs, io::Write};
+use std::{collections::{HashMap, HashSet}, fs, io::Write};
 
-use crate::{protocol::Protocol, struct_::Struct, types::Types};
+use crate::{
+    constraint::{checked_type, ConstraintKind},
+    enum_::Enum,
+    field::{escape_string, Field},
+    protocol::Protocol,
+    struct_::{Struct, StructType},
+    types::Types,
+};
 
-const FILE_HEADER: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
-#let encode-int(value) = {
+/// Integer codecs for the Typst versions without `int.from-bytes` and `int.to-bytes`
+const INT_CODECS: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
+#let $encode-int(value) = {
   bytes((
     calc.rem(calc.quo(value, 0x1000000), 0x100),
     calc.rem(calc.quo(value, 0x10000), 0x100),
@@ -13,68 +21,99 @@ const FILE_HEADER: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
 }
 
 /// Decodes a big-endian integer from the given bytes.
-#let decode-int(bytes) = {
+#let $decode-int(bytes, offset) = {
   let result = 0
-  for byte in array(bytes.slice(0,4)) {
+  for byte in array(bytes.slice(offset, offset + 4)) {
     result = result * 256 + byte
   }
   if (result > 0x7FFFFFFF) { // the number is negative
     result = result - 0x100000000
   }
-  (result, 4)
+  (result, offset + 4)
 }
 
-/// Encodes a string into bytes.
-#let encode-string(value) = {
-	bytes(value) + bytes((0x00,))
+/// Encodes an integer on `size` bytes in big-endian, using two's complement if `signed` is true.
+/// Panics if the value doesn't fit in the given size.
+#let $encode-sized-int(value, size, signed) = {
+  let fits = if signed {
+    // every Typst integer fits in a signed 64 bits integer
+    size == 8 or (value >= -calc.pow(2, size * 8 - 1) and value < calc.pow(2, size * 8 - 1))
+  } else {
+    value >= 0 and (size == 8 or value < calc.pow(2, size * 8))
+  }
+  if not fits {
+    let kind = if signed { \"signed\" } else { \"unsigned\" }
+    panic(\"Integer \" + str(value) + \" does not fit in a \" + kind + \" integer of \" + str(size) + \" bytes\")
+  }
+  let result = ()
+  for i in range(0, size) {
+    let byte = calc.rem(value, 0x100)
+    if byte < 0 {
+      byte += 0x100
+    }
+    result.push(byte)
+    value = calc.quo(value - byte, 0x100)
+  }
+  bytes(result.rev())
 }
 
-/// Decodes a string from the given bytes.
-#let decode-string(bytes) = {
-	let length = 0
-	for byte in array(bytes) {
-		length = length + 1
-		if byte == 0x00 {
-			break
-		}
-	}
-	if length == 0 {
-		(\"\", 1)
-	} else { 
-		(str(bytes.slice(0, length - 1)), length)
-	}
-	//(array(bytes.slice(0, length - 1)), length)
+/// Decodes a big-endian integer of `size` bytes, using two's complement if `signed` is true.
+/// Panics if the value doesn't fit in a Typst integer.
+#let $decode-sized-int(bytes, offset, size, signed) = {
+  let data = array(bytes.slice(offset, offset + size))
+  if not signed and size == 8 and data.at(0) >= 0x80 {
+    panic(\"Unsigned integer of 8 bytes does not fit in a Typst integer\")
+  }
+  let result = if signed and data.at(0) >= 0x80 { data.at(0) - 0x100 } else { data.at(0) }
+  for byte in data.slice(1) {
+    result = result * 0x100 + byte
+  }
+  (result, offset + size)
 }
 
-/// Encodes a boolean into bytes
-#let encode-bool(value) = {
-  if value {
-	bytes((0x01,))
-  } else {
-	bytes((0x00,))
-  }
+";
+
+/// Integer codecs built on `int.from-bytes` and `int.to-bytes`
+const NATIVE_INT_CODECS: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
+#let $encode-int(value) = {
+  value.to-bytes(endian: \"big\", size: 4)
 }
 
-/// Decodes a boolean from the given bytes
-#let decode-bool(bytes) = {
-  if bytes.at(0) == 0x00 {
-	(false, 1)
+/// Decodes a big-endian integer from the given bytes.
+#let $decode-int(bytes, offset) = {
+  (int.from-bytes(bytes.slice(offset, offset + 4), endian: \"big\", signed: true), offset + 4)
+}
+
+/// Encodes an integer on `size` bytes in big-endian, using two's complement if `signed` is true.
+/// Panics if the value doesn't fit in the given size.
+#let $encode-sized-int(value, size, signed) = {
+  let fits = if signed {
+    // every Typst integer fits in a signed 64 bits integer
+    size == 8 or (value >= -calc.pow(2, size * 8 - 1) and value < calc.pow(2, size * 8 - 1))
   } else {
-	(true, 1)
+    value >= 0 and (size == 8 or value < calc.pow(2, size * 8))
   }
+  if not fits {
+    let kind = if signed { \"signed\" } else { \"unsigned\" }
+    panic(\"Integer \" + str(value) + \" does not fit in a \" + kind + \" integer of \" + str(size) + \" bytes\")
+  }
+  value.to-bytes(endian: \"big\", size: size)
 }
 
-/// Encodes a character into bytes
-#let encode-char(value) = {
-  bytes(value)
+/// Decodes a big-endian integer of `size` bytes, using two's complement if `signed` is true.
+/// Panics if the value doesn't fit in a Typst integer.
+#let $decode-sized-int(bytes, offset, size, signed) = {
+  let data = bytes.slice(offset, offset + size)
+  if not signed and size == 8 and data.at(0) >= 0x80 {
+    panic(\"Unsigned integer of 8 bytes does not fit in a Typst integer\")
+  }
+  (int.from-bytes(data, endian: \"big\", signed: signed), offset + size)
 }
 
-/// Decodes a character from the given bytes
-#let decode-char(bytes) = {
-  (bytes.at(0), 1)
-}
+";
 
-#let fractional-to-binary(fractional_part, max_dec, zero) = {
+/// Float codecs for the Typst versions without `float.from-bytes` and `float.to-bytes`
+const FLOAT_CODECS: &str = "#let $fractional-to-binary(fractional_part, max_dec, zero) = {
 	let result = 0
 	let i = 22 - max_dec
 	let first_one = 0
@@ -97,7 +136,7 @@ const FILE_HEADER: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
 	(result, first_one)
 }
 
-#let float-to-int(value) = {
+#let $float-to-int(value) = {
 	if value == 0 {
 		return 0
 	}
@@ -110,7 +149,7 @@ const FILE_HEADER: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
 	} else {
 		calc.floor(calc.log(base: 2, mantissa)) - 1
 	}
-	let (fractional_part, first_one) = fractional-to-binary(fractional_part, exponent, mantissa == 0)
+	let (fractional_part, first_one) = $fractional-to-binary(fractional_part, exponent, mantissa == 0)
 	mantissa *= calc.pow(2, 22 - exponent)
 	mantissa += fractional_part
 	if exponent == 0 {
@@ -120,7 +159,7 @@ const FILE_HEADER: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
 	return  sign * calc.pow(2, 31) + exponent * calc.pow(2, 23) + mantissa
 }
 
-#let mantissa-to-float(mantissa) = {
+#let $mantissa-to-float(mantissa) = {
 	let result = 1.0
 	for i in range(0,23) {
 		if calc.rem(mantissa, 2) == 1 {
@@ -131,7 +170,7 @@ const FILE_HEADER: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
 	result
 }
 
-#let int-to-float(value) = {
+#let $int-to-float(value) = {
 	if value == 0 {
 		return 0.0
 	}
@@ -143,38 +182,322 @@ const FILE_HEADER: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
 	}
 	let exponent = calc.rem(calc.quo(value, calc.pow(2, 23)), calc.pow(2, 8))
 	let mantissa = calc.rem(value, calc.pow(2, 23))
-	sign * calc.pow(2, exponent - 127) * mantissa-to-float(mantissa)
+	sign * calc.pow(2, exponent - 127) * $mantissa-to-float(mantissa)
 }
 
 /// Encodes a float into bytes
-#let encode-float(value) = {
-	encode-int(float-to-int(value))
+#let $encode-float(value) = {
+	$encode-int($float-to-int(value))
+}
+
+/// Decodes a float from the given bytes
+#let $decode-float(bytes, offset) = {
+	let (decoded, offset) = $decode-int(bytes, offset)
+	($int-to-float(decoded), offset)
 }
 
-#let encode-point(value) = {
-	encode-float(value.pt())
+/// Multiplies a float by 2^exponent in steps small enough to keep every intermediate result exact
+#let $scale-by-pow2(value, exponent) = {
+	while exponent > 512 {
+		value *= calc.pow(2.0, 512)
+		exponent -= 512
+	}
+	while exponent < -512 {
+		value /= calc.pow(2.0, 512)
+		exponent += 512
+	}
+	if exponent >= 0 {
+		value * calc.pow(2.0, exponent)
+	} else {
+		value / calc.pow(2.0, -exponent)
+	}
+}
+
+/// Encodes a float into 8 bytes following the IEEE-754 double precision format.
+/// Only multiplications by powers of two are used so the encoding is exact.
+#let $encode-double(value) = {
+	let value = float(value)
+	let negative = value < 0 or (value == 0 and repr(value).starts-with(regex(\"[-\\u{2212}]\")))
+	let magnitude = calc.abs(value)
+	let (exponent, mantissa) = if value != value {
+		(0x7FF, calc.pow(2, 51))
+	} else if magnitude > 1.7976931348623157e308 {
+		(0x7FF, 0)
+	} else if magnitude == 0 {
+		(0, 0)
+	} else {
+		let exponent = calc.floor(calc.log(magnitude, base: 2))
+		if $scale-by-pow2(1.0, exponent) > magnitude {
+			exponent -= 1
+		}
+		if $scale-by-pow2(1.0, exponent + 1) <= magnitude {
+			exponent += 1
+		}
+		if exponent < -1022 { // subnormal number
+			(0, int($scale-by-pow2(magnitude, 1074)))
+		} else {
+			(exponent + 1023, int($scale-by-pow2(magnitude, 52 - exponent)) - calc.pow(2, 52))
+		}
+	}
+	let sign = if negative { 0x80000000 } else { 0 }
+	let high = sign + exponent * 0x100000 + calc.quo(mantissa, 0x100000000)
+	let low = calc.rem(mantissa, 0x100000000)
+	$encode-sized-int(high, 4, false) + $encode-sized-int(low, 4, false)
+}
+
+/// Decodes an IEEE-754 double precision float from the given bytes
+#let $decode-double(bytes, offset) = {
+	let (high, _) = $decode-sized-int(bytes, offset, 4, false)
+	let (low, _) = $decode-sized-int(bytes, offset + 4, 4, false)
+	let exponent = calc.rem(calc.quo(high, 0x100000), 0x800)
+	let mantissa = calc.rem(high, 0x100000) * 0x100000000 + low
+	let magnitude = if exponent == 0x7FF {
+		let infinity = 1e308 * 10.0
+		if mantissa == 0 { infinity } else { infinity - infinity }
+	} else if exponent == 0 { // subnormal number
+		$scale-by-pow2(float(mantissa), -1074)
+	} else {
+		$scale-by-pow2(float(mantissa + calc.pow(2, 52)), exponent - 1075)
+	}
+	(if high >= 0x80000000 { -magnitude } else { magnitude }, offset + 8)
+}
+
+";
+
+/// Float codecs built on `float.from-bytes` and `float.to-bytes`
+const NATIVE_FLOAT_CODECS: &str = "/// Encodes a float into 4 bytes following the IEEE-754 single precision format
+#let $encode-float(value) = {
+	float(value).to-bytes(endian: \"big\", size: 4)
 }
 
 /// Decodes a float from the given bytes
-#let decode-float(bytes) = {
-	let (decoded, size) = decode-int(bytes)
-	(int-to-float(decoded), size)
+#let $decode-float(bytes, offset) = {
+	(float.from-bytes(bytes.slice(offset, offset + 4), endian: \"big\"), offset + 4)
+}
+
+/// Encodes a float into 8 bytes following the IEEE-754 double precision format
+#let $encode-double(value) = {
+	float(value).to-bytes(endian: \"big\", size: 8)
+}
+
+/// Decodes an IEEE-754 double precision float from the given bytes
+#let $decode-double(bytes, offset) = {
+	(float.from-bytes(bytes.slice(offset, offset + 8), endian: \"big\"), offset + 8)
+}
+
+";
+
+/// First Typst version with the `from-bytes` and `to-bytes` methods of integers and floats
+const NATIVE_CODECS_VERSION: (u32, u32) = (0, 11);
+
+const FILE_HEADER: &str = "#let $encode-i8(value) = $encode-sized-int(value, 1, true)
+#let $decode-i8(bytes, offset) = $decode-sized-int(bytes, offset, 1, true)
+#let $encode-u8(value) = $encode-sized-int(value, 1, false)
+#let $decode-u8(bytes, offset) = $decode-sized-int(bytes, offset, 1, false)
+#let $encode-i16(value) = $encode-sized-int(value, 2, true)
+#let $decode-i16(bytes, offset) = $decode-sized-int(bytes, offset, 2, true)
+#let $encode-u16(value) = $encode-sized-int(value, 2, false)
+#let $decode-u16(bytes, offset) = $decode-sized-int(bytes, offset, 2, false)
+#let $encode-i32(value) = $encode-sized-int(value, 4, true)
+#let $decode-i32(bytes, offset) = $decode-sized-int(bytes, offset, 4, true)
+#let $encode-u32(value) = $encode-sized-int(value, 4, false)
+#let $decode-u32(bytes, offset) = $decode-sized-int(bytes, offset, 4, false)
+#let $encode-i64(value) = $encode-sized-int(value, 8, true)
+#let $decode-i64(bytes, offset) = $decode-sized-int(bytes, offset, 8, true)
+#let $encode-u64(value) = $encode-sized-int(value, 8, false)
+#let $decode-u64(bytes, offset) = $decode-sized-int(bytes, offset, 8, false)
+
+/// Encodes a string into bytes.
+#let $encode-string(value) = {
+	bytes(value) + bytes((0x00,))
+}
+
+/// Decodes a string from the given bytes.
+#let $decode-string(bytes, offset) = {
+	let end = offset
+	while bytes.at(end) != 0x00 {
+		end += 1
+	}
+	(str(bytes.slice(offset, end)), end + 1)
 }
 
-#let decode-point(bytes) = {
-	let (value, size) = decode-float(bytes)
-	(value * 1pt, size)
+/// Encodes raw bytes, prefixed by their length
+#let $encode-bytes(value) = {
+	$encode-int(value.len()) + value
+}
+
+/// Decodes raw bytes from the given bytes with a single slice
+#let $decode-bytes(bytes, offset) = {
+	let (length, offset) = $decode-int(bytes, offset)
+	(bytes.slice(offset, offset + length), offset + length)
+}
+
+/// Encodes a boolean into bytes
+#let $encode-bool(value) = {
+  if value {
+	bytes((0x01,))
+  } else {
+	bytes((0x00,))
+  }
+}
+
+/// Decodes a boolean from the given bytes
+#let $decode-bool(bytes, offset) = {
+  if bytes.at(offset) == 0x00 {
+	(false, offset + 1)
+  } else {
+	(true, offset + 1)
+  }
+}
+
+/// Encodes a character into bytes
+#let $encode-char(value) = {
+  bytes(value)
+}
+
+/// Decodes a character from the given bytes
+#let $decode-char(bytes, offset) = {
+  (bytes.at(offset), offset + 1)
+}
+
+#let $encode-point(value) = {
+	$encode-float(value.pt())
+}
+
+#let $decode-point(bytes, offset) = {
+	let (value, offset) = $decode-float(bytes, offset)
+	(value * 1pt, offset)
+}
+
+/// Encodes a length as its absolute part in points followed by its part in em
+#let $encode-length(value) = {
+	$encode-float(value.abs.pt()) + $encode-float(value.em)
+}
+
+/// Decodes a length from the given bytes
+#let $decode-length(bytes, offset) = {
+	let (abs, offset) = $decode-float(bytes, offset)
+	let (em, offset) = $decode-float(bytes, offset)
+	(abs * 1pt + em * 1em, offset)
+}
+
+/// Encodes an angle in degrees
+#let $encode-angle(value) = {
+	$encode-float(value.deg())
+}
+
+/// Decodes an angle from the given bytes
+#let $decode-angle(bytes, offset) = {
+	let (value, offset) = $decode-float(bytes, offset)
+	(value * 1deg, offset)
+}
+
+/// Encodes a ratio, 100% is encoded as 1
+#let $encode-ratio(value) = {
+	$encode-float(value / 100%)
+}
+
+/// Decodes a ratio from the given bytes
+#let $decode-ratio(bytes, offset) = {
+	let (value, offset) = $decode-float(bytes, offset)
+	(value * 100%, offset)
+}
+
+/// Encodes a relative length as its length followed by its ratio, lengths and ratios are accepted too
+#let $encode-relative(value) = {
+	let value = value + 0pt + 0%
+	$encode-length(value.length) + $encode-ratio(value.ratio)
+}
+
+/// Decodes a relative length from the given bytes
+#let $decode-relative(bytes, offset) = {
+	let (length, offset) = $decode-length(bytes, offset)
+	let (ratio, offset) = $decode-ratio(bytes, offset)
+	(length + ratio, offset)
+}
+
+/// Encodes a fraction as its number of fr
+#let $encode-fraction(value) = {
+	$encode-float(value / 1fr)
+}
+
+/// Decodes a fraction from the given bytes
+#let $decode-fraction(bytes, offset) = {
+	let (value, offset) = $decode-float(bytes, offset)
+	(value * 1fr, offset)
+}
+
+/// Encodes a color as its RGBA components, on a byte each
+#let $encode-color(value) = {
+	bytes(rgb(value).components().map(c => int(calc.round(c / 100% * 255))))
+}
+
+/// Decodes a RGBA color from the given bytes
+#let $decode-color(bytes, offset) = {
+	(rgb(..array(bytes.slice(offset, offset + 4))), offset + 4)
+}
+
+/// Encodes a datetime as its year on 4 bytes followed by its month, day, hour, minute and second on a byte each
+/// The time of a datetime that only has a date is encoded as midnight
+#let $encode-datetime(value) = {
+	let time = (value.hour(), value.minute(), value.second()).map(x => if x == none { 0 } else { x })
+	$encode-i32(value.year()) + bytes((value.month(), value.day()) + time)
+}
+
+/// Decodes a datetime from the given bytes
+#let $decode-datetime(bytes, offset) = {
+	let (year, offset) = $decode-i32(bytes, offset)
+	let (month, day, hour, minute, second) = array(bytes.slice(offset, offset + 5))
+	(datetime(year: year, month: month, day: day, hour: hour, minute: minute, second: second), offset + 5)
+}
+
+/// Encodes the date of a datetime as its year on 4 bytes followed by its month and day on a byte each
+#let $encode-date(value) = {
+	$encode-i32(value.year()) + bytes((value.month(), value.day()))
+}
+
+/// Decodes a datetime that only has a date from the given bytes
+#let $decode-date(bytes, offset) = {
+	let (year, offset) = $decode-i32(bytes, offset)
+	let (month, day) = array(bytes.slice(offset, offset + 2))
+	(datetime(year: year, month: month, day: day), offset + 2)
+}
+
+/// Encodes a duration as its number of seconds on 8 bytes
+#let $encode-duration(value) = {
+	$encode-i64(int(calc.round(value.seconds())))
+}
+
+/// Decodes a duration from the given bytes
+#let $decode-duration(bytes, offset) = {
+	let (seconds, offset) = $decode-i64(bytes, offset)
+	(duration(seconds: seconds), offset)
 }
 
 /// Encodes a list of elements into bytes
-#let encode-list(arr, encoder) = {
-	let length = encode-int(arr.len())
+#let $encode-list(arr, encoder) = {
+	let length = $encode-int(arr.len())
 	let encoded = bytes(arr.map(encoder).map(array).flatten())
 	length + encoded
 }
 
+/// Encodes a list of exactly `length` elements into bytes, without its length
+#let $encode-fixed-list(arr, length, encoder) = {
+	if arr.len() != length {
+		panic(\"Expected an array of \" + str(length) + \" elements but got \" + str(arr.len()))
+	}
+	bytes(arr.map(encoder).map(array).flatten())
+}
+
+/// Encodes a dictionary into bytes, as its length followed by each key and value
+#let $encode-map(dict, encoder) = {
+	let length = $encode-int(dict.len())
+	let encoded = bytes(dict.pairs().map(((key, value)) => array($encode-string(key) + encoder(value))).flatten())
+	length + encoded
+}
+
 /// Encodes an optional value into bytes
-#let encode-optional(opt, encoder) = {
+#let $encode-optional(opt, encoder) = {
 	if opt == none {
 		bytes((0x00,))
 	} else {
@@ -183,123 +506,707 @@ const FILE_HEADER: &str = "/// Encodes a 32-bytes integer into big-endian bytes.
 }
 
 /// Decodes a list of elements from the given bytes
-#let decode-list(bytes, decoder) = {
-	let (length, length_size) = decode-int(bytes)
+#let $decode-list(bytes, offset, decoder) = {
+	let (length, offset) = $decode-int(bytes, offset)
+	let result = ()
+	for i in range(0, length) {
+		let (element, next) = decoder(bytes, offset)
+		result.push(element)
+		offset = next
+	}
+	(result, offset)
+}
+
+/// Decodes a list of exactly `length` elements from the given bytes
+#let $decode-fixed-list(bytes, offset, length, decoder) = {
 	let result = ()
-	let offset = length_size
 	for i in range(0, length) {
-		let (element, size) = decoder(bytes.slice(offset, bytes.len()))
+		let (element, next) = decoder(bytes, offset)
 		result.push(element)
-		offset += size
+		offset = next
+	}
+	(result, offset)
+}
+
+/// Decodes a dictionary from the given bytes
+#let $decode-map(bytes, offset, decoder) = {
+	let (length, offset) = $decode-int(bytes, offset)
+	let result = (:)
+	for i in range(0, length) {
+		let (key, next) = $decode-string(bytes, offset)
+		let (value, next) = decoder(bytes, next)
+		result.insert(key, value)
+		offset = next
 	}
 	(result, offset)
 }
 
 /// Decodes an optional value from the given bytes
-#let decode-optional(bytes, decoder) = {
-	let has_value = bytes.at(0) != 0x00
-	if has_value {
-		let (value, size) = decoder(bytes.slice(1, bytes.len()))
-		((value), size + 1)
+#let $decode-optional(bytes, offset, decoder) = {
+	if bytes.at(offset) != 0x00 {
+		decoder(bytes, offset + 1)
 	} else {
-		((none), 1)
+		(none, offset + 1)
 	}
 }
+
+/// Encodes the components of a color as floats, ratios are encoded between 0 and 1 and angles in degrees
+#let $encode-color-components(components) = {
+	components.map(c => if type(c) == ratio { c / 100% } else if type(c) == angle { c.deg() } else { c }).map($encode-float).sum()
+}
+
+/// Decodes the components of a color with `constructor`, each component is multiplied by its unit
+#let $decode-color-components(bytes, offset, constructor, units) = {
+	let (components, offset) = $decode-fixed-list(bytes, offset, units.len(), $decode-float)
+	(constructor(..components.zip(units).map(((c, unit)) => c * unit)), offset)
+}
+
+#let $encode-color-luma(value) = $encode-color-components(luma(value).components())
+#let $decode-color-luma(bytes, offset) = $decode-color-components(bytes, offset, luma, (100%, 100%))
+#let $encode-color-cmyk(value) = $encode-color-components(cmyk(value).components())
+#let $decode-color-cmyk(bytes, offset) = $decode-color-components(bytes, offset, cmyk, (100%, 100%, 100%, 100%))
+#let $encode-color-oklab(value) = $encode-color-components(oklab(value).components())
+#let $decode-color-oklab(bytes, offset) = $decode-color-components(bytes, offset, oklab, (100%, 1, 1, 100%))
+#let $encode-color-oklch(value) = $encode-color-components(oklch(value).components())
+#let $decode-color-oklch(bytes, offset) = $decode-color-components(bytes, offset, oklch, (100%, 1, 1deg, 100%))
+#let $encode-color-linear-rgb(value) = $encode-color-components(color.linear-rgb(value).components())
+#let $decode-color-linear-rgb(bytes, offset) = $decode-color-components(bytes, offset, color.linear-rgb, (100%, 100%, 100%, 100%))
+#let $encode-color-hsl(value) = $encode-color-components(color.hsl(value).components())
+#let $decode-color-hsl(bytes, offset) = $decode-color-components(bytes, offset, color.hsl, (1deg, 100%, 100%, 100%))
+#let $encode-color-hsv(value) = $encode-color-components(color.hsv(value).components())
+#let $decode-color-hsv(bytes, offset) = $decode-color-components(bytes, offset, color.hsv, (1deg, 100%, 100%, 100%))
+
+/// Encodes a text run, a dictionary with a `text` and optional `bold`, `italic`, `color` and `size` keys
+#let $encode-text-run(value) = {
+	(
+		$encode-string(value.text)
+		+ $encode-bool(value.at(\"bold\", default: false))
+		+ $encode-bool(value.at(\"italic\", default: false))
+		+ $encode-optional(value.at(\"color\", default: none), $encode-color)
+		+ $encode-optional(value.at(\"size\", default: none), $encode-length)
+	)
+}
+
+/// Decodes a text run from the given bytes, its color and size are none when they are not set
+#let $decode-text-run(bytes, offset) = {
+	let (body, offset) = $decode-string(bytes, offset)
+	let (bold, offset) = $decode-bool(bytes, offset)
+	let (italic, offset) = $decode-bool(bytes, offset)
+	let (fill, offset) = $decode-optional(bytes, offset, $decode-color)
+	let (font-size, offset) = $decode-optional(bytes, offset, $decode-length)
+	((text: body, bold: bold, italic: italic, color: fill, size: font-size), offset)
+}
+
+/// Renders a list of text runs as content, the runs without color or size keep the current ones
+#let $render-text-runs(runs) = {
+	for run in runs {
+		let style = (:)
+		if run.at(\"bold\", default: false) {
+			style.insert(\"weight\", \"bold\")
+		}
+		if run.at(\"italic\", default: false) {
+			style.insert(\"style\", \"italic\")
+		}
+		if run.at(\"color\", default: none) != none {
+			style.insert(\"fill\", run.color)
+		}
+		if run.at(\"size\", default: none) != none {
+			style.insert(\"size\", run.size)
+		}
+		text(..style, run.text)
+	}
+}
+
+/// Panics if `value` is set and is not between `min` and `max`, `name` is the field in the message
+#let $check-range(value, min, max, name) = {
+  if value != none and (value < min or value > max) {
+    panic(name + \" must be between \" + repr(min) + \" and \" + repr(max) + \", got \" + repr(value))
+  }
+}
+
+/// Panics if `value` is set and its length is not between `min` and `max`, `name` is the field in the message
+#let $check-len(value, min, max, name) = {
+  if value != none and (value.len() < min or value.len() > max) {
+    panic(\"The length of \" + name + \" must be between \" + str(min) + \" and \" + str(max) + \", got \" + str(value.len()))
+  }
+}
 ";
 
-fn generate_header(file: &mut fs::File) -> Result<(), std::io::Error> {
-    file.write(FILE_HEADER.as_bytes())?;
+/// Write the helpers, `$` in their code is replaced by the prefix of the functions
+/// The integer and float codecs use the built-in conversions when the targeted Typst version has them
+fn generate_header(file: &mut fs::File, names: &Names, typst_version: Option<(u32, u32)>) -> Result<(), std::io::Error> {
+    let codecs = if typst_version.is_some_and(|version| version >= NATIVE_CODECS_VERSION) {
+        [NATIVE_INT_CODECS, NATIVE_FLOAT_CODECS]
+    } else {
+        [INT_CODECS, FLOAT_CODECS]
+    };
+    for code in codecs.into_iter().chain([FILE_HEADER]) {
+        file.write_all(code.replace('$', &names.prefix).as_bytes())?;
+    }
     Ok(())
 }
 
-fn generate_dictionary_serialisation(
+/// Write the doc comment of the `kind` function of a declaration in a format tidy can read
+/// `items` are the documented fields or variants, nothing is written if nothing is documented
+fn generate_doc(
     file: &mut fs::File,
-    name: &str,
-    s: &Struct,
+    kind: &str,
+    doc: &Option<String>,
+    items: &[(&str, Option<String>)],
+    value_type: &str,
 ) -> Result<(), std::io::Error> {
-    file.write(format!("#let encode-{}(value) = {{\n", name).as_bytes())?;
-    file.write(b"  ")?;
-    let mut first = true;
-    for (name, t, _) in s.iter() {
-        if !first {
-            file.write(b" + ")?;
+    if doc.is_none() && items.iter().all(|(_, doc)| doc.is_none()) {
+        return Ok(());
+    }
+    let mut lines = Vec::new();
+    if let Some(doc) = doc {
+        lines.extend(doc.lines().map(str::to_string));
+        lines.push(String::new());
+    }
+    for (name, doc) in items {
+        if let Some(doc) = doc {
+            let mut doc = doc.lines();
+            lines.push(format!("/ {}: {}", name, doc.next().unwrap_or_default()));
+            lines.extend(doc.map(|line| format!("  {}", line)));
         }
-        match t {
-            Types::Array(t) => {
-                file.write(
-                    format!("encode-list(value.at(\"{}\"), encode-{})", name, t.to_typst()).as_bytes(),
-                )?;
-            }
-			Types::Optional(t) => {
-				file.write(
-					format!("encode-optional(value.at(\"{}\", default: none), encode-{})", name, t.to_typst()).as_bytes(),
-				)?;
-			}
-            Types::Struct(t) => {
-                file.write(format!("encode-{}(value.at(\"{}\"))", t, name).as_bytes())?;
+    }
+    if kind == "encode" {
+        lines.push(format!("- value ({}): The value to encode", value_type));
+        lines.push("-> bytes".to_string());
+    } else {
+        lines.push("- bytes (bytes): The bytes to decode, they can be followed by other values".to_string());
+        lines.push("- offset (int): The index of the first byte of the value".to_string());
+        lines.push("-> array".to_string());
+    }
+    for line in lines {
+        file.write_all(format!("/// {}", line).trim_end().as_bytes())?;
+        file.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Doc of a field in the doc comment of its struct, deprecated fields and the version of the fields added with `@since` are marked
+fn field_doc(field: &Field) -> Option<String> {
+    let deprecated = field.attribute("deprecated").map(|attribute| match attribute.string() {
+        Some(reason) => format!("*Deprecated*: {}", reason),
+        None => "*Deprecated*".to_string(),
+    });
+    let since = field.since().map(|version| format!("*Since*: version {}", version));
+    let lines = [field.doc.clone(), deprecated, since].into_iter().flatten().collect::<Vec<_>>();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// Write the doc comment of the `kind` function of a struct, union or protocol
+fn generate_struct_doc(file: &mut fs::File, kind: &str, s: &Struct) -> Result<(), std::io::Error> {
+    let fields = s.iter().map(|field| (field.typst_name(), field_doc(field))).collect::<Vec<_>>();
+    generate_doc(file, kind, &s.doc, &fields, "dictionary")
+}
+
+/// Write the constants of the protocol as variables
+fn generate_constants(file: &mut fs::File, p: &Protocol, names: &Names) -> Result<(), std::io::Error> {
+    for (name, constant) in p.ordered_constants() {
+        for line in constant.doc.iter().flat_map(|doc| doc.lines()) {
+            file.write_all(format!("/// {}", line).trim_end().as_bytes())?;
+            file.write_all(b"\n")?;
+        }
+        file.write_all(format!("#let {}{} = {}\n", names.prefix, name, constant.value.to_typst(&constant.type_)).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Write the variants list and the encode/decode functions of an enum
+/// Variants are represented by their name as a string on the Typst side
+fn generate_enum(file: &mut fs::File, name: &str, e: &Enum, names: &Names) -> Result<(), std::io::Error> {
+    file.write_all(format!("#let {}{}-variants = (", names.prefix, name).as_bytes())?;
+    for variant in e.iter() {
+        file.write_all(format!("\"{}\", ", variant.name).as_bytes())?;
+    }
+    file.write_all(b")\n")?;
+    let variants = e.iter().map(|variant| (variant.name, variant.doc.clone())).collect::<Vec<_>>();
+    generate_doc(file, "encode", &e.doc, &variants, "str")?;
+    file.write_all(format!("#let {}encode-{}(value) = {{\n", names.prefix, name).as_bytes())?;
+    file.write_all(format!("  let index = {}{}-variants.position(variant => variant == value)\n", names.prefix, name).as_bytes())?;
+    file.write_all(b"  if index == none {\n")?;
+    file.write_all(format!("    panic(\"Unknown variant for enum {}: \" + repr(value))\n", name).as_bytes())?;
+    file.write_all(b"  }\n")?;
+    file.write_all(b"  bytes((index,))\n}\n")?;
+    generate_doc(file, "decode", &e.doc, &variants, "str")?;
+    file.write_all(format!("#let {}decode-{}(bytes, offset) = {{\n", names.prefix, name).as_bytes())?;
+    file.write_all(b"  let index = bytes.at(offset)\n")?;
+    file.write_all(format!("  if index >= {}{}-variants.len() {{\n", names.prefix, name).as_bytes())?;
+    file.write_all(format!("    panic(\"Unknown variant index for enum {}: \" + str(index))\n", name).as_bytes())?;
+    file.write_all(b"  }\n")?;
+    file.write_all(format!("  ({}{}-variants.at(index), offset + 1)\n}}\n", names.prefix, name).as_bytes())?;
+    Ok(())
+}
+
+/// Names of the generated functions, prefixed by the package of the protocol
+/// A Typst function can only call itself and the functions defined before it, so the
+/// structs of a group that use each other are encoded and decoded by a shared function
+/// taking the struct name
+#[derive(Clone)]
+struct Names<'a> {
+    prefix: String,
+    groups: HashMap<&'a str, String>,
+    /// Struct used by each alias, the functions of an alias are written after the functions of its struct
+    alias_structs: HashMap<String, String>,
+    /// Protocols encoding each struct, they are named in the messages of the constraints of its fields
+    protocols: HashMap<String, Vec<String>>,
+    /// Structs whose functions are being written
+    writing: Vec<&'a str>,
+}
+
+impl<'a> Names<'a> {
+    fn new(package: Option<&str>, groups: &[Vec<&'a str>], p: &Protocol) -> Names<'a> {
+        let mut functions = HashMap::new();
+        for group in groups.iter().filter(|group| group.len() > 1) {
+            for name in group {
+                functions.insert(*name, format!("group-{}", group.join("-")));
             }
-            _ => {
-                file.write(format!("encode-{}(value.at(\"{}\"))", t.to_typst(), name).as_bytes())?;
+        }
+        let alias_structs = p
+            .ordered_aliases()
+            .filter_map(|(name, alias)| alias.type_.struct_name().map(|s| (name.to_string(), s.to_string())))
+            .collect();
+        let mut protocols: HashMap<String, Vec<String>> = HashMap::new();
+        for (protocol, s) in p.protocols().filter(|(_, s)| need_encoder(s)) {
+            let mut stack = s.iter().filter_map(|field| field.type_.struct_name()).collect::<Vec<_>>();
+            while let Some(name) = stack.pop() {
+                let users = protocols.entry(name.to_string()).or_default();
+                if !users.iter().any(|user| user == protocol) {
+                    users.push(protocol.to_string());
+                    if let Some(s) = p.get_struct(name) {
+                        stack.extend(s.iter().filter_map(|field| field.type_.struct_name()));
+                    }
+                }
             }
         }
-        first = false;
+        Names {
+            prefix: package.map(|package| format!("{}-", package)).unwrap_or_default(),
+            groups: functions,
+            alias_structs,
+            protocols,
+            writing: Vec::new(),
+        }
+    }
+
+    /// Names used while writing the functions of a group of structs
+    fn writing(&self, group: &[&'a str]) -> Names<'a> {
+        Names { writing: group.to_vec(), ..self.clone() }
+    }
+
+    /// Check if the struct is encoded and decoded by the function of its group
+    fn in_group(&self, name: &str) -> bool {
+        self.groups.contains_key(name)
+    }
+
+    /// Check if an alias is encoded inline because its functions come after the structs being written
+    fn inline_alias(&self, name: &str) -> bool {
+        self.alias_structs.get(name).is_some_and(|s| self.writing.contains(&s.as_str()))
+    }
+
+    /// Return the Typst expression that calls the `kind` function of the struct with `argument`
+    fn call(&self, kind: &str, name: &str, argument: &str) -> String {
+        match self.groups.get(name) {
+            Some(group) => format!("{}{}-{}(\"{}\", {})", self.prefix, kind, group, name, argument),
+            None => format!("{}{}-{}({})", self.prefix, kind, name, argument),
+        }
+    }
+}
+
+/// Typst type of the values of the given type, as written in the doc comments
+fn value_type(t: &Types) -> String {
+    match t.resolved() {
+        Types::Int | Types::Integer { .. } => "int".to_string(),
+        Types::Float | Types::Double => "float".to_string(),
+        Types::Point | Types::Length => "length".to_string(),
+        Types::Angle => "angle".to_string(),
+        Types::Ratio => "ratio".to_string(),
+        Types::Relative => "relative".to_string(),
+        Types::Fraction => "fraction".to_string(),
+        Types::Color(_) => "color".to_string(),
+        Types::Datetime | Types::Date => "datetime".to_string(),
+        Types::Duration => "duration".to_string(),
+        Types::TextRun => "dictionary".to_string(),
+        Types::Bool => "bool".to_string(),
+        Types::Char | Types::String | Types::Enum(_) => "str".to_string(),
+        Types::Bytes => "bytes".to_string(),
+        Types::Array(_) | Types::FixedArray(..) => "array".to_string(),
+        Types::Map(_) | Types::Struct(_) => "dictionary".to_string(),
+        Types::Optional(t) => format!("{}, none", value_type(t)),
+        Types::Alias(..) => unreachable!("Aliases are resolved"),
+    }
+}
+
+/// Write the encode and decode functions of an alias, they encode values like the aliased type
+/// An alias of a struct only gets the functions the struct has
+fn generate_alias(
+    file: &mut fs::File,
+    name: &str,
+    t: &Types,
+    doc: &Option<String>,
+    s: Option<&Struct>,
+    names: &Names,
+) -> Result<(), std::io::Error> {
+    if s.is_none_or(need_encoder) {
+        generate_doc(file, "encode", doc, &[], &value_type(t))?;
+        file.write_all(format!("#let {}encode-{}(value) = {}\n", names.prefix, name, encode_value(t, "value", names)).as_bytes())?;
+    }
+    if s.is_none_or(need_decoder) {
+        generate_doc(file, "decode", doc, &[], &value_type(t))?;
+        file.write_all(format!("#let {}decode-{}(bytes, offset) = {}\n", names.prefix, name, decode_value(t, "bytes, offset", names)).as_bytes())?;
     }
-    file.write(b"\n}\n")?;
     Ok(())
 }
 
-fn generate_dictionary_deserialisaion(
+/// Names of the aliases used by the type
+fn alias_names(t: &Types) -> Vec<&str> {
+    match t {
+        Types::Alias(name, t) => {
+            let mut names = alias_names(t);
+            names.push(name);
+            names
+        }
+        Types::Array(t) | Types::FixedArray(t, _) | Types::Optional(t) | Types::Map(t) => alias_names(t),
+        _ => Vec::new(),
+    }
+}
+
+/// Write the functions of the aliases of the structs of `group`, or of the aliases that don't use a struct,
+/// after the functions of the aliases they use
+fn generate_aliases<'a>(
     file: &mut fs::File,
+    p: &'a Protocol,
+    group: Option<&[&str]>,
+    structs: &HashMap<&str, &Struct>,
+    written: &mut HashSet<&'a str>,
+    names: &Names,
+) -> Result<(), std::io::Error> {
+    for (name, alias) in p.ordered_aliases() {
+        let s = alias.type_.struct_name();
+        if s.is_some_and(|s| group.is_some_and(|group| group.contains(&s))) || (s.is_none() && group.is_none()) {
+            for used in alias_names(&alias.type_).into_iter().chain([name]) {
+                if written.insert(used) {
+                    let alias = p.get_alias(used);
+                    let s = alias.type_.struct_name().map(|name| structs[name]);
+                    generate_alias(file, used, &alias.type_, &alias.doc, s, names)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Return the Typst function that encodes the given type
+/// Lists and optionals are wrapped in a closure so they can be nested
+fn encoder(t: &Types, names: &Names) -> String {
+    match t {
+        Types::Alias(name, t) if names.inline_alias(name) => encoder(t, names),
+        Types::Alias(name, _) => format!("{}encode-{}", names.prefix, name),
+        Types::Array(_) | Types::FixedArray(..) | Types::Optional(_) | Types::Map(_) => format!("x => {}", encode_value(t, "x", names)),
+        Types::Struct(name) if names.in_group(name) => format!("x => {}", encode_value(t, "x", names)),
+        _ => format!("{}encode-{}", names.prefix, t.to_typst()),
+    }
+}
+
+/// Return the Typst function that decodes the given type
+fn decoder(t: &Types, names: &Names) -> String {
+    match t {
+        Types::Alias(name, t) if names.inline_alias(name) => decoder(t, names),
+        Types::Alias(name, _) => format!("{}decode-{}", names.prefix, name),
+        Types::Array(_) | Types::FixedArray(..) | Types::Optional(_) | Types::Map(_) => format!("(b, o) => {}", decode_value(t, "b, o", names)),
+        Types::Struct(name) if names.in_group(name) => format!("(b, o) => {}", decode_value(t, "b, o", names)),
+        _ => format!("{}decode-{}", names.prefix, t.to_typst()),
+    }
+}
+
+/// Return the Typst expression that encodes `value` as the given type
+fn encode_value(t: &Types, value: &str, names: &Names) -> String {
+    match t {
+        Types::Alias(name, t) if names.inline_alias(name) => encode_value(t, value, names),
+        Types::Alias(name, _) => format!("{}encode-{}({})", names.prefix, name, value),
+        Types::Array(t) => format!("{}encode-list({}, {})", names.prefix, value, encoder(t, names)),
+        Types::FixedArray(t, size) => format!("{}encode-fixed-list({}, {}, {})", names.prefix, value, size, encoder(t, names)),
+        Types::Optional(t) => format!("{}encode-optional({}, {})", names.prefix, value, encoder(t, names)),
+        Types::Map(t) => format!("{}encode-map({}, {})", names.prefix, value, encoder(t, names)),
+        Types::Struct(name) => names.call("encode", name, value),
+        _ => format!("{}encode-{}({})", names.prefix, t.to_typst(), value),
+    }
+}
+
+/// Return the Typst expression that decodes the given type, `arguments` are the bytes and the offset to read them from
+/// The expression returns the decoded value and the offset of the next value
+fn decode_value(t: &Types, arguments: &str, names: &Names) -> String {
+    match t {
+        Types::Alias(name, t) if names.inline_alias(name) => decode_value(t, arguments, names),
+        Types::Alias(name, _) => format!("{}decode-{}({})", names.prefix, name, arguments),
+        Types::Array(t) => format!("{}decode-list({}, {})", names.prefix, arguments, decoder(t, names)),
+        Types::FixedArray(t, size) => format!("{}decode-fixed-list({}, {}, {})", names.prefix, arguments, size, decoder(t, names)),
+        Types::Optional(t) => format!("{}decode-optional({}, {})", names.prefix, arguments, decoder(t, names)),
+        Types::Map(t) => format!("{}decode-map({}, {})", names.prefix, arguments, decoder(t, names)),
+        Types::Struct(name) => names.call("decode", name, arguments),
+        _ => format!("{}decode-{}({})", names.prefix, t.to_typst(), arguments),
+    }
+}
+
+/// Return the Typst expression that reads a field from the `value` dictionary
+/// Optional fields and fields with a default value can be omitted from the dictionary
+fn field_value(field: &Field) -> String {
+    let name = escape_string(field.typst_name());
+    match (field.type_.resolved(), &field.default) {
+        (_, Some(default)) => format!("value.at(\"{}\", default: {})", name, default.to_typst(&field.type_)),
+        (Types::Optional(_), None) => format!("value.at(\"{}\", default: none)", name),
+        _ => format!("value.at(\"{}\")", name),
+    }
+}
+
+/// Return the Typst value of a field added with `@since` that is missing from a payload
+/// `bytes` is the decoded bytes, so empty bytes are sliced from it
+fn missing_value(field: &Field) -> String {
+    match (field.type_.resolved(), &field.default) {
+        (_, Some(default)) => default.to_typst(&field.type_),
+        (Types::Array(_), None) => "()".to_string(),
+        (Types::Map(_), None) => "(:)".to_string(),
+        (Types::Bytes, None) => "bytes.slice(0, 0)".to_string(),
+        _ => "none".to_string(),
+    }
+}
+
+/// Return the key of a field in a Typst dictionary literal
+/// Names given with `@typst_name` are quoted as they can be any string
+fn dictionary_key(field: &Field) -> String {
+    if field.attribute("typst_name").is_some() {
+        format!("\"{}\"", escape_string(field.typst_name()))
+    } else {
+        field.name.to_string()
+    }
+}
+
+/// Write the calls that check the constraints of a field before it is encoded
+/// `value` is the Typst expression of the field and `name` is the struct, union or protocol owning it,
+/// the message also names the protocols encoding a struct as its encoder doesn't know which one is called
+fn generate_constraints(
+    file: &mut impl Write,
+    field: &Field,
+    value: &str,
+    name: &str,
+    tab: &str,
+    names: &Names,
+) -> Result<(), std::io::Error> {
+    let t = checked_type(&field.type_);
+    let field_name = match names.protocols.get(name) {
+        Some(protocols) => format!("{}.{} in {}", name, field.typst_name(), protocols.join(" or ")),
+        None => format!("{}.{}", name, field.typst_name()),
+    };
+    let field_name = escape_string(&field_name);
+    for constraint in &field.constraints {
+        let (kind, min, max) = match &constraint.kind {
+            ConstraintKind::Range(min, max) => ("range", min.to_typst(t), max.to_typst(t)),
+            ConstraintKind::Length(min, max) => ("len", min.to_string(), max.to_string()),
+        };
+        file.write_all(format!("{}{}check-{}({}, {}, {}, \"{}\")\n", tab, names.prefix, kind, value, min, max, field_name).as_bytes())?;
+    }
+    Ok(())
+}
+
+fn generate_dictionary_serialisation(
+    file: &mut impl Write,
     name: &str,
     s: &Struct,
+    names: &Names,
 ) -> Result<(), std::io::Error> {
-    file.write(format!("#let decode-{}(bytes) = {{\n", name).as_bytes())?;
-    file.write(b"  let offset = 0\n")?;
-    for (name, t, _) in s.iter() {
-        file.write(format!("  let (f_{}, size) = ", name).as_bytes())?;
-        match t {
-            Types::Array(t) => {
-                file.write(
-                    format!(
-                        "decode-list(bytes.slice(offset, bytes.len()), decode-{})",
-                        t.to_typst()
-                    )
-                    .as_bytes(),
-                )?;
-            }
-			Types::Optional(t) => {
-				file.write(
-					format!(
-						"decode-optional(bytes.slice(offset, bytes.len()), decode-{})",
-						t.to_typst()
-					)
-					.as_bytes(),
-				)?;
-			}
-            Types::Struct(t) => {
-                file.write(format!("decode-{}(bytes.slice(offset, bytes.len()))", t).as_bytes())?;
-            }
-            _ => {
-                file.write(
-                    format!("decode-{}(bytes.slice(offset, bytes.len()))", t.to_typst()).as_bytes(),
-                )?;
-            }
+    for field in s.iter() {
+        generate_constraints(file, field, &field_value(field), name, "  ", names)?;
+    }
+    file.write_all(b"  ")?;
+    let mut first = true;
+    for field in s.iter() {
+        if !first {
+            file.write_all(b" + ")?;
+        }
+        file.write_all(encode_value(&field.type_, &field_value(field), names).as_bytes())?;
+        first = false;
+    }
+    file.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Write the decode function body of a struct, the fields start at `offset` in the bytes
+fn generate_dictionary_deserialisaion(file: &mut impl Write, s: &Struct, names: &Names) -> Result<(), std::io::Error> {
+    for field in s.iter() {
+        let decode = decode_value(&field.type_, "bytes, offset", names);
+        file.write_all(format!("  let (f_{}, offset) = ", field.name).as_bytes())?;
+        // payloads of older versions end before the fields added with @since
+        if field.since().is_some() {
+            let value = missing_value(field);
+            file.write_all(format!("if offset < bytes.len() {{ {} }} else {{ ({}, offset) }}", decode, value).as_bytes())?;
+        } else {
+            file.write_all(decode.as_bytes())?;
         }
-        file.write(b"\n  offset += size\n")?;
+        file.write_all(b"\n")?;
     }
-	file.write(b"  ((\n")?;
-	for (name, _, _) in s.iter() {
-		file.write(format!("    {}: f_{},\n", name, name).as_bytes())?;
+	file.write_all(b"  ((\n")?;
+	for field in s.iter() {
+		file.write_all(format!("    {}: f_{},\n", dictionary_key(field), field.name).as_bytes())?;
 	}
-    file.write(b"  ), offset)\n}\n")?;
+    file.write_all(b"  ), offset)\n")?;
+    Ok(())
+}
+
+/// Write the encode function of a union, the value is a dictionary like `(kind: "name", value: ...)`
+fn generate_union_serialisation(
+    file: &mut impl Write,
+    name: &str,
+    s: &Struct,
+    names: &Names,
+) -> Result<(), std::io::Error> {
+    file.write_all(b"  let kind = value.at(\"kind\")\n  ")?;
+    for (index, field) in s.iter().enumerate() {
+        let t = &field.type_;
+        file.write_all(format!("if kind == \"{}\" {{\n", escape_string(field.typst_name())).as_bytes())?;
+        let value = match t.resolved() {
+            Types::Optional(_) => "value.at(\"value\", default: none)",
+            _ => "value.at(\"value\")",
+        };
+        generate_constraints(file, field, value, name, "    ", names)?;
+        file.write_all(format!("    bytes(({},)) + {}\n  }} else ", index, encode_value(t, value, names)).as_bytes())?;
+    }
+    file.write_all(b"{\n")?;
+    file.write_all(format!("    panic(\"Unknown kind for union {}: \" + repr(kind))\n", name).as_bytes())?;
+    file.write_all(b"  }\n")?;
+    Ok(())
+}
+
+/// Write the decode function of a union, the first byte tells which field follows
+fn generate_union_deserialisation(
+    file: &mut impl Write,
+    name: &str,
+    s: &Struct,
+    names: &Names,
+) -> Result<(), std::io::Error> {
+    file.write_all(b"  let kind = bytes.at(offset)\n  ")?;
+    for (index, field) in s.iter().enumerate() {
+        let t = &field.type_;
+        file.write_all(format!("if kind == {} {{\n", index).as_bytes())?;
+        file.write_all(format!("    let (value, offset) = {}\n", decode_value(t, "bytes, offset + 1", names)).as_bytes())?;
+        file.write_all(format!("    ((kind: \"{}\", value: value), offset)\n  }} else ", escape_string(field.typst_name())).as_bytes())?;
+    }
+    file.write_all(b"{\n")?;
+    file.write_all(format!("    panic(\"Unknown kind index for union {}: \" + str(kind))\n", name).as_bytes())?;
+    file.write_all(b"  }\n")?;
     Ok(())
 }
 
-fn generate(file: &mut fs::File, name: &str, s: &Struct) -> Result<(), std::io::Error> {
-    if s.decoder {
-        generate_dictionary_serialisation(file, name, s)?;
+/// Check if the Typst encode function of the struct is needed
+fn need_encoder(s: &Struct) -> bool {
+    s.decoder
+}
+
+/// Check if the Typst decode function of the struct is needed
+/// Structs that are not used by any protocol only get a decode function
+fn need_decoder(s: &Struct) -> bool {
+    s.encoder || !s.decoder
+}
+
+/// Write the body of the encode function of a struct or union, it reads the `value` variable
+fn generate_encoder_body(file: &mut impl Write, name: &str, s: &Struct, names: &Names) -> Result<(), std::io::Error> {
+    if let StructType::Union = s.get_type() {
+        generate_union_serialisation(file, name, s, names)
+    } else {
+        generate_dictionary_serialisation(file, name, s, names)
+    }
+}
+
+/// Write the body of the decode function of a struct or union, it reads the `bytes` from `offset`
+fn generate_decoder_body(file: &mut impl Write, name: &str, s: &Struct, names: &Names) -> Result<(), std::io::Error> {
+    if let StructType::Union = s.get_type() {
+        generate_union_deserialisation(file, name, s, names)
     } else {
-        generate_dictionary_deserialisaion(file, name, s)?;
+        generate_dictionary_deserialisaion(file, s, names)
+    }
+}
+
+/// Write the encode function if the struct is decoded in C and the decode function if it's encoded in C
+fn generate(file: &mut fs::File, name: &str, s: &Struct, names: &Names) -> Result<(), std::io::Error> {
+    if need_encoder(s) {
+        generate_struct_doc(file, "encode", s)?;
+        file.write_all(format!("#let {}encode-{}(value) = {{\n", names.prefix, name).as_bytes())?;
+        // the version of a protocol is sent before its fields
+        if let Some(version) = s.version {
+            file.write_all(format!("  {}encode-int({})\n", names.prefix, version).as_bytes())?;
+        }
+        generate_encoder_body(file, name, s, names)?;
+        file.write_all(b"}\n")?;
+    }
+    if need_decoder(s) {
+        generate_struct_doc(file, "decode", s)?;
+        file.write_all(format!("#let {}decode-{}(bytes, offset) = {{\n", names.prefix, name).as_bytes())?;
+        if let Some(version) = s.version {
+            file.write_all(format!("  let (version, offset) = {}decode-int(bytes, offset)\n", names.prefix).as_bytes())?;
+            // older versions are accepted when the fields they don't have were added with @since
+            let (check, expected) = match s.oldest_version() {
+                Some(oldest) if oldest < version => (format!("version < {} or version > {}", oldest, version), format!("{} to {}", oldest, version)),
+                _ => (format!("version != {}", version), version.to_string()),
+            };
+            file.write_all(format!("  if {} {{\n", check).as_bytes())?;
+            file.write_all(format!("    panic(\"Version mismatch for protocol {}: expected version {}, got version \" + str(version))\n", name, expected).as_bytes())?;
+            file.write_all(b"  }\n")?;
+            generate_dictionary_deserialisaion(file, s, names)?;
+        } else {
+            generate_decoder_body(file, name, s, names)?;
+        }
+        file.write_all(b"}\n")?;
+    }
+    Ok(())
+}
+
+/// Write the shared `kind` function of a group of structs that use each other,
+/// each struct body is written by `body` in its own branch
+fn generate_group_function<F>(
+    file: &mut fs::File,
+    kind: &str,
+    argument: &str,
+    members: &[(&str, &Struct)],
+    names: &Names,
+    mut body: F,
+) -> Result<(), std::io::Error>
+where
+    F: FnMut(&mut Vec<u8>, &str, &Struct, &Names) -> Result<(), std::io::Error>,
+{
+    let function = &names.groups[members[0].0];
+    file.write_all(format!("#let {}{}-{}(name, {}) = {{\n  ", names.prefix, kind, function, argument).as_bytes())?;
+    for (name, s) in members {
+        file.write_all(format!("if name == \"{}\" {{\n", name).as_bytes())?;
+        let mut content = Vec::new();
+        body(&mut content, name, s, names)?;
+        for line in String::from_utf8_lossy(&content).lines() {
+            file.write_all(format!("  {}\n", line).as_bytes())?;
+        }
+        file.write_all(b"  } else ")?;
+    }
+    file.write_all(b"{\n")?;
+    file.write_all(b"    panic(\"Unknown struct: \" + name)\n")?;
+    file.write_all(b"  }\n}\n")?;
+    for (name, s) in members {
+        generate_struct_doc(file, kind, s)?;
+        file.write_all(format!("#let {}{}-{}({}) = {}\n", names.prefix, kind, name, argument, names.call(kind, name, argument)).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Write the functions of a group of structs that use each other
+fn generate_group(file: &mut fs::File, members: &[(&str, &Struct)], names: &Names) -> Result<(), std::io::Error> {
+    // structs of a group use each other so they all have the same encoding type
+    let s = members[0].1;
+    if need_encoder(s) {
+        generate_group_function(file, "encode", "value", members, names, |file, name, s, names| {
+            generate_encoder_body(file, name, s, names)
+        })?;
+    }
+    if need_decoder(s) {
+        generate_group_function(file, "decode", "bytes, offset", members, names, |file, name, s, names| {
+            generate_decoder_body(file, name, s, names)
+        })?;
     }
     Ok(())
 }
@@ -307,12 +1214,29 @@ fn generate(file: &mut fs::File, name: &str, s: &Struct) -> Result<(), std::io::
 pub fn generate_protocol(path: &str, p: &Protocol) -> Result<(), std::io::Error> {
     let path = format!("{}/protocol.typ", path);
     let mut file = fs::File::create(path)?;
-    generate_header(&mut file)?;
-    for (name, s) in p.ordered_structs() {
-        generate(&mut file, name, s)?;
+    let struct_groups = p.struct_groups();
+    let names = Names::new(p.package(), &struct_groups, p);
+    generate_header(&mut file, &names, p.typst_version())?;
+    generate_constants(&mut file, p, &names)?;
+    for (name, e) in p.ordered_enums() {
+        generate_enum(&mut file, name, e, &names)?;
+    }
+    let structs = p.structs().map(|(name, s)| (*name, s)).collect::<HashMap<_, _>>();
+    let mut written_aliases = HashSet::new();
+    generate_aliases(&mut file, p, None, &structs, &mut written_aliases, &names)?;
+    for group in &struct_groups {
+        // the aliases of the structs of the group are not defined yet, so they are inlined in the group
+        let group_names = names.writing(group);
+        if group.len() == 1 {
+            generate(&mut file, group[0], structs[group[0]], &group_names)?;
+        } else {
+            let members = group.iter().map(|name| (*name, structs[name])).collect::<Vec<_>>();
+            generate_group(&mut file, &members, &group_names)?;
+        }
+        generate_aliases(&mut file, p, Some(group), &structs, &mut written_aliases, &names)?;
     }
     for (name, s) in p.protocols() {
-        generate(&mut file, name, s)?;
+        generate(&mut file, name, s, &names)?;
     }
     Ok(())
 }
@@ -6,9 +6,27 @@ use protocol::Protocol;
 pub mod struct_;
 use struct_::{ProtocolType, Struct, StructType};
 
+pub mod field;
+use field::Field;
+
+pub mod attribute;
+
+pub mod constraint;
+
+pub mod enum_;
+use enum_::Enum;
+
+pub mod alias;
+use alias::Alias;
+
+pub mod constant;
+use constant::Constant;
+
 pub mod types;
 use types::Types;
 
+pub mod compatibility;
+
 pub mod generator;
 
 pub mod argparse;
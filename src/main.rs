@@ -3,8 +3,10 @@ use std::{fs::metadata, process::exit};
 use clap::Parser;
 use wasmpg::{
     argparse::Argparse,
+    compatibility::check_compatibility,
     generator::{cgenerator, typstgenerator},
-    parser::ProtocolParser,
+    parser::{ProtocolParser, Sources},
+    protocol::Protocol,
 };
 
 /// check if the given path is a folder and writable
@@ -21,14 +23,37 @@ fn check_folder(path: &str) {
 			}
 		}
 		Err(e) => {
-			eprintln!("Error: Invalid output directory {}", e.to_string());
+			eprintln!("Error: Invalid output directory {}", e);
+			exit(1);
+		}
+	}
+}
+
+/// check if the given prefix can be used in C and Typst names
+fn check_prefix(prefix: &str) {
+	let mut chars = prefix.chars();
+	let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+	if !valid {
+		eprintln!("Error: Invalid prefix \"{}\", it must be a valid identifier", prefix);
+		exit(1);
+	}
+}
+
+/// Parse a Typst version like `0.11` or `0.11.1` into its major and minor numbers
+fn parse_typst_version(version: &str) -> (u32, u32) {
+	let mut numbers = version.split('.').map(|number| number.parse::<u32>());
+	match (numbers.next(), numbers.next(), numbers.next(), numbers.next()) {
+		(Some(Ok(major)), Some(Ok(minor)), None | Some(Ok(_)), None) => (major, minor),
+		_ => {
+			eprintln!("Error: Invalid Typst version \"{}\", expected a version like 0.11", version);
 			exit(1);
 		}
 	}
 }
 
 /// Call the generators functions to generate the C and Typst protocols
-fn generate_protocols(c_folder: String, protocol: wasmpg::protocol::Protocol<'_>, typst_folder: String) {
+fn generate_protocols(c_folder: String, protocol: Protocol<'_>, typst_folder: String) {
 	match cgenerator::generate_protocol(&c_folder, &protocol) {
 		Ok(_) => {
 			println!("Generated C protocol");
@@ -47,20 +72,47 @@ fn generate_protocols(c_folder: String, protocol: wasmpg::protocol::Protocol<'_>
 	}
 }
 
+/// Print a warning for each change of the protocol file that breaks the decoding of the payloads of its previous version
+fn check_previous(path: &str, protocol: &Protocol<'_>) {
+	let file = match std::fs::read_to_string(path) {
+		Ok(file) => file,
+		Err(e) => {
+			eprintln!("Error: {}", e);
+			exit(1);
+		}
+	};
+	let mut sources = Sources::default();
+	match ProtocolParser::parse_protocol_file(file.as_str(), Some(path), &mut sources) {
+		Ok(previous) => {
+			for warning in check_compatibility(&previous, protocol) {
+				println!("Warning: {}", warning);
+			}
+		}
+		Err(e) => {
+			println!("Error in the previous protocol file: {:?}", e);
+			exit(1);
+		}
+	}
+}
+
 fn main() {
     let args = Argparse::parse();
 
-    let file = match std::fs::read_to_string(args.input) {
+    let file = match std::fs::read_to_string(&args.input) {
         Ok(file) => file,
         Err(e) => {
-            eprintln!("Error: {}", e.to_string());
+            eprintln!("Error: {}", e);
 			exit(1);
         }
     };
 	
-	if args.check {
-		match ProtocolParser::parse_protocol(file.as_str()) {
-			Ok(_) => {
+	let mut sources = Sources::default();
+	if args.check || args.previous.is_some() {
+		match ProtocolParser::parse_protocol_file(file.as_str(), Some(&args.input), &mut sources) {
+			Ok(protocol) => {
+				if let Some(previous) = &args.previous {
+					check_previous(previous, &protocol);
+				}
 				println!("Protocol is valid");
 			}
 			Err(e) => {
@@ -86,11 +138,21 @@ fn main() {
 		".".to_string()
 	};
 
+	if let Some(prefix) = &args.prefix {
+		check_prefix(prefix);
+	}
+	let typst_version = args.typst_version.as_deref().map(parse_typst_version);
 	check_folder(&c_folder);
 	check_folder(&typst_folder);
-    let result = ProtocolParser::parse_protocol(file.as_str());
+    let result = ProtocolParser::parse_protocol_file(file.as_str(), Some(&args.input), &mut sources);
     match result {
-        Ok(protocol) => {
+        Ok(mut protocol) => {
+			if let Some(prefix) = &args.prefix {
+				protocol.set_package(prefix);
+			}
+			if let Some(version) = typst_version {
+				protocol.set_typst_version(version);
+			}
 			if args.check {
 				println!("Protocol is valid");
 			} else {
@@ -1,19 +1,148 @@
+use crate::Alias;
+use crate::Constant;
 use crate::Protocol;
 use crate::Types;
-use crate::{ProtocolType, Struct, StructType};
+use crate::attribute::Attribute;
+use crate::constraint::Constraint;
+use crate::field::Value;
+use crate::types::ColorSpace;
+use crate::{Enum, Field, ProtocolType, Struct, StructType};
 use pest::error::{Error, ErrorVariant};
+use pest::iterators::{Pair, Pairs};
 use pest::Parser;
 use pest_derive::Parser;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[grammar = "parser/protocol.pest"]
 pub struct ProtocolParser;
 
+/// An imported file, read before the files are parsed
+struct Source {
+    canonical: PathBuf,
+    /// Path of the file as written in the first import of the file, used in the errors
+    path: String,
+    /// Content of the file or the error raised while reading it
+    content: Result<String, String>,
+}
+
+/// Contents of the files imported by a protocol file
+/// The parsed protocol borrows them, so they are owned by the caller and must outlive the protocol
+#[derive(Default)]
+pub struct Sources {
+    files: Vec<Source>,
+}
+
+impl Sources {
+    /// Read the files imported by `content` and by the files they import, relative to `dir`
+    /// Files that can't be parsed are skipped, their errors are reported when they are parsed
+    fn load_imports(&mut self, content: &str, dir: &Path) {
+        let Ok(mut program) = ProtocolParser::parse(Rule::protocol, content) else {
+            return;
+        };
+        let paths = program
+            .next()
+            .unwrap()
+            .into_inner()
+            .filter(|declaration| declaration.as_rule() == Rule::IMPORT_DEF)
+            .map(|declaration| {
+                let literal = declaration.into_inner().next().unwrap().as_str();
+                dir.join(&literal[1..literal.len() - 1])
+            })
+            .collect::<Vec<_>>();
+        for path in paths {
+            let Ok(canonical) = path.canonicalize() else {
+                continue;
+            };
+            if self.get(&canonical).is_some() {
+                continue;
+            }
+            let content = std::fs::read_to_string(&path).map_err(|e| e.to_string());
+            self.files.push(Source {
+                canonical,
+                path: path.display().to_string(),
+                content: content.clone(),
+            });
+            if let Ok(content) = content {
+                self.load_imports(&content, path.parent().unwrap_or(Path::new("")));
+            }
+        }
+    }
+
+    fn get(&self, canonical: &Path) -> Option<&Source> {
+        self.files.iter().find(|source| source.canonical == canonical)
+    }
+}
+
+/// State shared by the files imported while parsing a protocol
+struct Imports<'a> {
+    /// Files currently being parsed, used to detect import cycles
+    stack: Vec<PathBuf>,
+    /// Files already imported, a file imported twice is only parsed once
+    done: HashSet<PathBuf>,
+    /// Content of the imported files, also used to find the file of an error
+    sources: &'a Sources,
+    /// Constant declarations of every file, they are added before the other declarations
+    constants: Vec<Pair<'a, Rule>>,
+    /// Other declarations of every file, parsed once every constant is known
+    declarations: Vec<Pair<'a, Rule>>,
+}
+
+impl<'a> Imports<'a> {
+    fn new(sources: &'a Sources) -> Imports<'a> {
+        Imports {
+            stack: Vec::new(),
+            done: HashSet::new(),
+            sources,
+            constants: Vec::new(),
+            declarations: Vec::new(),
+        }
+    }
+
+    /// Add the path of the file containing the span to an error
+    fn with_path(&self, error: Error<Rule>, span: pest::Span<'_>, root: Option<&str>) -> Error<Rule> {
+        let input = span.get_input().as_ptr();
+        let path = self
+            .sources
+            .files
+            .iter()
+            .find(|source| source.content.as_ref().is_ok_and(|content| std::ptr::eq(content.as_ptr(), input)))
+            .map(|source| source.path.as_str())
+            .or(root);
+        match path {
+            Some(path) => error.with_path(path),
+            None => error,
+        }
+    }
+
+    /// Add the path of the file containing the span to an error that doesn't have one
+    fn locate(&self, error: Error<Rule>, span: pest::Span<'_>, root: Option<&str>) -> Error<Rule> {
+        match error.path() {
+            Some(_) => error,
+            None => self.with_path(error, span, root),
+        }
+    }
+}
+
 impl ProtocolParser {
-    pub fn parse_protocol<'a>(file: &'a str) -> Result<Protocol<'a>, Error<Rule>> {
-        let protocol = ProtocolParser::parse(Rule::protocol, file)?.next().unwrap();
+    /// Parse a protocol, imports are resolved relative to the current directory and read into `sources`
+    pub fn parse_protocol<'a>(file: &'a str, sources: &'a mut Sources) -> Result<Protocol<'a>, Error<Rule>> {
+        ProtocolParser::parse_protocol_file(file, None, sources)
+    }
+
+    /// Parse the content of the protocol file at `path`, imports are resolved relative to it
+    /// and errors give the name of the file they come from
+    /// The imported files are read into `sources`, which the protocol borrows
+    pub fn parse_protocol_file<'a>(file: &'a str, path: Option<&str>, sources: &'a mut Sources) -> Result<Protocol<'a>, Error<Rule>> {
+        let program = ProtocolParser::parse(Rule::protocol, file)
+            .map_err(|e| match path {
+                Some(path) => e.with_path(path),
+                None => e,
+            })?
+            .next()
+            .unwrap();
 
-        use pest::iterators::Pair;
         fn parse_protocol_type(pair: Pair<Rule>) -> ProtocolType {
             match pair.into_inner().next().unwrap().as_rule() {
                 Rule::PROTOCOL_C => ProtocolType::C,
@@ -23,50 +152,224 @@ impl ProtocolParser {
             }
         }
 
+        fn parse_type(pair: Pair<Rule>, protocol: &Protocol) -> Result<Types, Error<Rule>> {
+            let mut pair = pair.into_inner();
+            let type_tok = pair.next().unwrap();
+            let mut field_type = if type_tok.as_rule() == Rule::MAP_TYPE {
+                let mut map = type_tok.into_inner();
+                map.next(); // the key is always a string
+                let value_type = parse_type(map.next().unwrap(), protocol)?;
+                Types::Map(Box::new(parse_suffixes(value_type, map, protocol)?))
+            } else if type_tok.as_rule() == Rule::COLOR_TYPE {
+                let space = type_tok.into_inner().next().map(|space| ColorSpace::parse(space.as_str()).unwrap());
+                Types::Color(space.unwrap_or(ColorSpace::Rgb))
+            } else {
+                // struct and enum names are resolved once the whole file is parsed
+                Types::parse(type_tok.as_str())
+            };
+            if pair.next().is_some() {
+                field_type = Types::Optional(Box::new(field_type));
+            }
+            Ok(field_type)
+        }
+
+        fn parse_suffixes<'a>(
+            mut field_type: Types,
+            suffixes: impl DoubleEndedIterator<Item = Pair<'a, Rule>>,
+            protocol: &Protocol,
+        ) -> Result<Types, Error<Rule>> {
+            // the first suffix is the outermost one, like in a C declaration
+            for suffix in suffixes.rev() {
+                field_type = match suffix.as_rule() {
+                    Rule::LIST => Types::Array(Box::new(field_type)),
+                    Rule::FIXED_LIST => {
+                        let size_tok = suffix.into_inner().next().unwrap();
+                        let size = match size_tok.as_rule() {
+                            Rule::IDENT => {
+                                let name = Value::Enum(size_tok.as_str().to_string());
+                                match resolve_constant(name, size_tok.as_span(), protocol)? {
+                                    Value::Int(size) => usize::try_from(size).ok(),
+                                    _ => None,
+                                }
+                            }
+                            _ => size_tok.as_str().parse::<usize>().ok(),
+                        };
+                        let size = match size {
+                            Some(size) if size > 0 => size,
+                            _ => {
+                                return Err(Error::new_from_span(
+                                    ErrorVariant::CustomError {
+                                        message: format!("Invalid array size \"{}\"", size_tok.as_str()),
+                                    },
+                                    size_tok.as_span(),
+                                ))
+                            }
+                        };
+                        Types::FixedArray(Box::new(field_type), size)
+                    }
+                    Rule::OPTIONAL => Types::Optional(Box::new(field_type)),
+                    _ => unreachable!(),
+                };
+            }
+            Ok(field_type)
+        }
+
+        /// Parse a value, default values are checked against the field type once names are resolved
+        fn parse_value(pair: Pair<Rule>) -> Result<Value, Error<Rule>> {
+            let span = pair.as_span();
+            let error = |message: String| Error::new_from_span(ErrorVariant::CustomError { message }, span);
+            let value = pair.into_inner().next().unwrap();
+            let text = value.as_str();
+            match value.as_rule() {
+                // infinite values have no literal in C
+                Rule::NUMBER if text.contains(['.', 'e', 'E']) => match text.parse::<f64>() {
+                    Ok(f) if f.is_finite() => Ok(Value::Float(f)),
+                    Ok(_) => Err(error("Number too large".to_string())),
+                    Err(_) => Err(error("Invalid number".to_string())),
+                },
+                Rule::NUMBER => text
+                    .parse::<i128>()
+                    .map(Value::Int)
+                    .map_err(|_| error("Number too large".to_string())),
+                Rule::BOOLEAN => Ok(Value::Bool(text == "true")),
+                Rule::STRING_LITERAL => {
+                    let mut string = String::new();
+                    let mut chars = text[1..text.len() - 1].chars();
+                    while let Some(c) = chars.next() {
+                        if c != '\\' {
+                            string.push(c);
+                            continue;
+                        }
+                        match chars.next() {
+                            Some('n') => string.push('\n'),
+                            Some('r') => string.push('\r'),
+                            Some('t') => string.push('\t'),
+                            Some(c @ ('"' | '\\')) => string.push(c),
+                            c => return Err(error(format!("Unknown escape sequence \"\\{}\"", c.unwrap_or_default()))),
+                        }
+                    }
+                    Ok(Value::String(string))
+                }
+                Rule::IDENT => Ok(Value::Enum(text.to_string())),
+                _ => unreachable!(),
+            }
+        }
+
+        /// Replace a name by the value of the constant it refers to, other values are kept
+        /// The constants of every file are added before the declarations using them are parsed
+        fn resolve_constant(value: Value, span: pest::Span, protocol: &Protocol) -> Result<Value, Error<Rule>> {
+            match value {
+                Value::Enum(name) => match protocol.get_constant(&name) {
+                    Some(constant) => Ok(constant.value.clone()),
+                    None => Err(Error::new_from_span(
+                        ErrorVariant::CustomError {
+                            message: format!("Constant \"{}\" not defined", name),
+                        },
+                        span,
+                    )),
+                },
+                value => Ok(value),
+            }
+        }
+
+        /// Parse a value that can be the name of a constant
+        fn parse_constant_value(pair: Pair<Rule>, protocol: &Protocol) -> Result<Value, Error<Rule>> {
+            let span = pair.as_span();
+            resolve_constant(parse_value(pair)?, span, protocol)
+        }
+
+        /// Parse a version, it is sent as a 32 bits integer
+        fn parse_version(pair: Pair<Rule>) -> Result<u32, Error<Rule>> {
+            let number = pair.into_inner().next().unwrap();
+            match number.as_str().parse::<u32>() {
+                Ok(version) if version <= i32::MAX as u32 => Ok(version),
+                _ => Err(Error::new_from_span(
+                    ErrorVariant::CustomError {
+                        message: format!("Invalid version \"{}\", expected an integer between 0 and {}", number.as_str(), i32::MAX),
+                    },
+                    number.as_span(),
+                )),
+            }
+        }
+
+        /// Parse the doc comment at the start of the pairs if there is one
+        fn parse_doc(pairs: &mut Pairs<Rule>) -> Option<String> {
+            if pairs.peek()?.as_rule() != Rule::DOC {
+                return None;
+            }
+            let lines = pairs.next().unwrap().into_inner().map(|line| {
+                let line = &line.as_str()[3..];
+                line.strip_prefix(' ').unwrap_or(line).trim_end()
+            });
+            Some(lines.collect::<Vec<_>>().join("\n"))
+        }
+
+        fn parse_attribute<'a>(pair: Pair<'a, Rule>, protocol: &Protocol) -> Result<Attribute<'a>, Error<Rule>> {
+            let pos = pair.as_span();
+            let mut pair = pair.into_inner();
+            let name = pair.next().unwrap().into_inner().next().unwrap().as_str();
+            let args = pair.map(|arg| parse_constant_value(arg, protocol)).collect::<Result<Vec<_>, _>>()?;
+            Attribute::new(name, args, pos)
+                .map_err(|message| Error::new_from_span(ErrorVariant::CustomError { message }, pos))
+        }
+
+        fn parse_constraint<'a>(pair: Pair<'a, Rule>, protocol: &Protocol) -> Result<Constraint<'a>, Error<Rule>> {
+            let pos = pair.as_span();
+            let rule = pair.as_rule();
+            let mut bounds = pair.into_inner().map(|bound| parse_constant_value(bound, protocol));
+            let mut bound = || bounds.next().unwrap();
+            let constraint = match rule {
+                Rule::RANGE_CONSTRAINT => Constraint::range(bound()?, bound()?, pos),
+                Rule::MAX_CONSTRAINT => Constraint::length(Value::Int(0), bound()?, pos),
+                _ => Constraint::length(bound()?, bound()?, pos),
+            };
+            constraint.map_err(|message| Error::new_from_span(ErrorVariant::CustomError { message }, pos))
+        }
+
         fn parse_fields<'a>(
             pair: Pair<'a, Rule>,
             struct_type: StructType,
+			pos: pest::Span<'a>,
             protocol: &Protocol,
-			pos: pest::Span<'a>
         ) -> Result<Struct<'a>, Error<Rule>> {
             let mut fields = Struct::new(struct_type, pos);
             for pair in pair.into_inner() {
+                if pair.as_rule() == Rule::VERSION_DEF {
+                    fields.version = Some(parse_version(pair)?);
+                    continue;
+                }
                 let mut pair = pair.into_inner(); // get the block content
-                let type_tok = pair.next().unwrap();
-                let pos = type_tok.as_span();
-                let mut field_type = Types::parse(type_tok.as_str());
-                if let Types::Struct(ref name) = field_type {
-                    if !protocol.has_struct(&name) {
+                let doc = parse_doc(&mut pair);
+                let mut attributes: Vec<Attribute> = Vec::new();
+                while pair.peek().is_some_and(|p| p.as_rule() == Rule::ATTRIBUTE) {
+                    let attribute = parse_attribute(pair.next().unwrap(), protocol)?;
+                    if attributes.iter().any(|a| a.name == attribute.name) {
                         return Err(Error::new_from_span(
                             ErrorVariant::CustomError {
-                                message: format!("Struct \"{}\" not found", name),
+                                message: format!("Attribute \"{}\" already set", attribute.name),
                             },
-                            pos,
+                            attribute.pos,
                         ));
                     }
+                    attributes.push(attribute);
                 }
-                let optional = pair.peek().map_or(false, |p| p.as_rule() == Rule::OPTIONAL);
-                if optional {
-                    field_type = Types::Optional(Box::new(field_type));
-                    pair.next(); 
-                }
+                let type_tok = pair.next().unwrap();
+                let pos = type_tok.as_span();
+                let field_type = parse_type(type_tok, protocol)?;
                 let name = pair.next().unwrap().as_str();
-
-                if let Some(_) = pair.next() {
-                    if optional {
-                        return Err(Error::new_from_span(
-                            ErrorVariant::CustomError {
-                                message: format!(
-                                    "Field \"{}\" cannot be both optional and a list",
-                                    name
-                                ),
-                            },
-                            pos,
-                        ));
+                let mut suffixes = Vec::new();
+                let mut constraints = Vec::new();
+                let mut default = None;
+                for pair in pair {
+                    match pair.as_rule() {
+                        Rule::RANGE_CONSTRAINT | Rule::MAX_CONSTRAINT | Rule::LEN_CONSTRAINT => {
+                            constraints.push(parse_constraint(pair, protocol)?);
+                        }
+                        Rule::VALUE => default = Some(pair),
+                        _ => suffixes.push(pair),
                     }
-                    // we are in list mode
-                    field_type = Types::Array(Box::new(field_type));
                 }
+                let field_type = parse_suffixes(field_type, suffixes.into_iter(), protocol)?;
                 if fields.has_field(name) {
                     return Err(Error::new_from_span(
                         ErrorVariant::CustomError {
@@ -75,7 +378,23 @@ impl ProtocolParser {
                         pos,
                     ));
                 }
-                fields.add_field(name, field_type, pos);
+                let mut field = Field::new(name, field_type, pos);
+                field.doc = doc;
+                field.attributes = attributes;
+                field.constraints = constraints;
+                if let Some(default) = default {
+                    if let StructType::Union = struct_type {
+                        return Err(Error::new_from_span(
+                            ErrorVariant::CustomError {
+                                message: "Union fields cannot have a default value".to_string(),
+                            },
+                            default.as_span(),
+                        ));
+                    }
+                    field.default_pos = Some(default.as_span());
+                    field.default = Some(parse_value(default)?);
+                }
+                fields.add_field(field);
             }
             match fields.get_type() {
                 StructType::Protocol(ProtocolType::C) => {
@@ -84,51 +403,291 @@ impl ProtocolParser {
                 StructType::Protocol(ProtocolType::Typst) => {
                     fields.encoder = true;
                 }
+                StructType::Protocol(ProtocolType::Bidirectional) => {
+                    fields.encoder = true;
+                    fields.decoder = true;
+                }
                 _ => {}
             }
             Ok(fields)
         }
 
-        fn parse_protocol(program: Pair<Rule>) -> Result<Protocol, Error<Rule>> {
-            let mut protocol = Protocol::default();
-            for declarations in program.into_inner() {
-				let pos = declarations.as_span();
-                match declarations.as_rule() {
-                    Rule::STRUCT_DEF => {
-                        let mut struct_def = declarations.into_inner();
-                        let name = struct_def.next().unwrap().as_str();
-						protocol.pre_add_struct(name, StructType::Struct, pos);
-                        protocol.add_struct(
-                            name,
-                            parse_fields(struct_def.next().unwrap(), StructType::Struct, &protocol, pos)?,
-                        ).map_err(|(msg, pos)| Error::new_from_span(
+        fn parse_enum<'a>(pair: Pair<'a, Rule>, pos: pest::Span<'a>) -> Result<Enum<'a>, Error<Rule>> {
+            let mut enum_ = Enum::new(pos);
+            for variant in pair.into_inner() {
+                let mut variant = variant.into_inner();
+                let doc = parse_doc(&mut variant);
+                let variant = variant.next().unwrap();
+                let name = variant.as_str();
+                if enum_.has_variant(name) {
+                    return Err(Error::new_from_span(
+                        ErrorVariant::CustomError {
+                            message: format!("Variant \"{}\" already defined", name),
+                        },
+                        variant.as_span(),
+                    ));
+                }
+                enum_.add_variant(name, variant.as_span(), doc);
+            }
+            if enum_.len() > 256 {
+                return Err(Error::new_from_span(
+                    ErrorVariant::CustomError {
+                        message: "An enum cannot have more than 256 variants".to_string(),
+                    },
+                    pos,
+                ));
+            }
+            Ok(enum_)
+        }
+
+        /// Parse an imported file and add its declarations to the protocol
+        fn parse_import<'a>(
+            pair: Pair<'a, Rule>,
+            protocol: &mut Protocol<'a>,
+            dir: &Path,
+            imports: &mut Imports<'a>,
+        ) -> Result<(), Error<Rule>> {
+            let pos = pair.as_span();
+            let error = |message: String| Error::new_from_span(ErrorVariant::CustomError { message }, pos);
+            let literal = pair.into_inner().next().unwrap().as_str();
+            let path = dir.join(&literal[1..literal.len() - 1]);
+            let canonical = path
+                .canonicalize()
+                .map_err(|e| error(format!("Cannot import \"{}\": {}", path.display(), e)))?;
+            if let Some(start) = imports.stack.iter().position(|p| *p == canonical) {
+                let cycle = imports.stack[start..]
+                    .iter()
+                    .chain(std::iter::once(&canonical))
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>();
+                return Err(error(format!("Import cycle detected: {}", cycle.join(" -> "))));
+            }
+            if !imports.done.insert(canonical.clone()) {
+                return Ok(());
+            }
+            // the files were read before parsing, so the declarations can borrow their content
+            let Some(source) = imports.sources.get(&canonical) else {
+                return Err(error(format!("Cannot import \"{}\"", path.display())));
+            };
+            let content = source
+                .content
+                .as_deref()
+                .map_err(|e| error(format!("Cannot import \"{}\": {}", path.display(), e)))?;
+            let display = source.path.clone();
+            let program = ProtocolParser::parse(Rule::protocol, content)
+                .map_err(|e| e.with_path(&display))?
+                .next()
+                .unwrap();
+            let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            imports.stack.push(canonical);
+            collect_declarations(program, protocol, &dir, imports).map_err(|e| match e.path() {
+                Some(_) => e,
+                None => e.with_path(&display),
+            })?;
+            imports.stack.pop();
+            Ok(())
+        }
+
+        /// Parse the imports of a file and keep its other declarations, and the ones of the files it imports,
+        /// to parse them once every constant is known
+        fn collect_declarations<'a>(
+            program: Pair<'a, Rule>,
+            protocol: &mut Protocol<'a>,
+            dir: &Path,
+            imports: &mut Imports<'a>,
+        ) -> Result<(), Error<Rule>> {
+            for declaration in program.into_inner() {
+                match declaration.as_rule() {
+                    Rule::IMPORT_DEF => parse_import(declaration, protocol, dir, imports)?,
+                    // only the package and version of the parsed file are used, they are read before its declarations
+                    Rule::PACKAGE_DEF | Rule::VERSION_DEF => {}
+                    Rule::CONST_DEF => imports.constants.push(declaration),
+                    _ => imports.declarations.push(declaration),
+                }
+            }
+            Ok(())
+        }
+
+        /// Add the constants of every file, a constant can be defined from another one declared anywhere
+        fn parse_constants<'a>(
+            declarations: &[Pair<'a, Rule>],
+            protocol: &mut Protocol<'a>,
+            imports: &Imports<'a>,
+            root: Option<&str>,
+        ) -> Result<(), Error<Rule>> {
+            let mut pending = Vec::new();
+            for declaration in declarations {
+                let pos = declaration.as_span();
+                let mut const_def = declaration.clone().into_inner();
+                let doc = parse_doc(&mut const_def);
+                let type_tok = const_def.next().unwrap();
+                let const_type = Types::parse(type_tok.as_str());
+                // bytes and Typst values like lengths have no literal in the protocol file
+                if !matches!(
+                    const_type,
+                    Types::Int | Types::Integer { .. } | Types::Float | Types::Double | Types::Point | Types::Bool | Types::Char | Types::String
+                ) {
+                    return Err(imports.locate(
+                        Error::new_from_span(
+                            ErrorVariant::CustomError {
+                                message: format!("A constant cannot be of type {:?}", const_type),
+                            },
+                            type_tok.as_span(),
+                        ),
+                        pos,
+                        root,
+                    ));
+                }
+                let name = const_def.next().unwrap().as_str();
+                let value_tok = const_def.next().unwrap();
+                let span = value_tok.as_span();
+                let value = parse_value(value_tok).map_err(|e| imports.locate(e, pos, root))?;
+                pending.push((name, const_type, value, span, pos, doc));
+            }
+            // constants defined from other constants are added after them
+            while !pending.is_empty() {
+                let count = pending.len();
+                let mut remaining = Vec::new();
+                for (name, const_type, value, span, pos, doc) in pending {
+                    if matches!(&value, Value::Enum(other) if protocol.get_constant(other).is_none()) {
+                        remaining.push((name, const_type, value, span, pos, doc));
+                        continue;
+                    }
+                    let value = resolve_constant(value, span, protocol)
+                        .map_err(|e| imports.locate(e, pos, root))?
+                        .resolve(&const_type, None)
+                        .map_err(|message| imports.locate(Error::new_from_span(ErrorVariant::CustomError { message }, span), pos, root))?;
+                    let mut constant = Constant::new(const_type, value, pos);
+                    constant.doc = doc;
+                    protocol.add_constant(name, constant).map_err(|(msg, pos)| imports.locate(
+                        Error::new_from_span(ErrorVariant::CustomError { message: msg }, pos),
+                        pos,
+                        root,
+                    ))?;
+                }
+                if remaining.len() == count {
+                    let (name, _, value, span, pos, _) = &remaining[0];
+                    let Value::Enum(other) = value else { unreachable!() };
+                    let message = if remaining.iter().any(|(pending, ..)| pending == other) {
+                        format!("Constant \"{}\" is defined from itself", name)
+                    } else {
+                        format!("Constant \"{}\" not defined", other)
+                    };
+                    return Err(imports.locate(Error::new_from_span(ErrorVariant::CustomError { message }, *span), *pos, root));
+                }
+                pending = remaining;
+            }
+            Ok(())
+        }
+
+        /// Parse a declaration other than an import or a constant and add it to the protocol
+        fn parse_declaration<'a>(declaration: Pair<'a, Rule>, protocol: &mut Protocol<'a>) -> Result<(), Error<Rule>> {
+            let pos = declaration.as_span();
+            match declaration.as_rule() {
+                Rule::STRUCT_DEF | Rule::UNION_DEF => {
+                    let struct_type = if declaration.as_rule() == Rule::UNION_DEF {
+                        StructType::Union
+                    } else {
+                        StructType::Struct
+                    };
+                    let mut struct_def = declaration.into_inner();
+                    let doc = parse_doc(&mut struct_def);
+                    let name = struct_def.next().unwrap().as_str();
+                    let mut struct_ = parse_fields(struct_def.next().unwrap(), struct_type, pos, protocol)?;
+                    struct_.doc = doc;
+                    if let StructType::Union = struct_.get_type() {
+                        if struct_.fields().is_empty() || struct_.fields().len() > 256 {
+                            return Err(Error::new_from_span(
+                                ErrorVariant::CustomError {
+                                    message: "A union must have between 1 and 256 fields".to_string(),
+                                },
+                                pos,
+                            ));
+                        }
+                    }
+                    protocol.add_struct(name, struct_).map_err(|(msg, pos)| Error::new_from_span(
 							ErrorVariant::CustomError { message: msg },
 							pos,
 						))?;
-                    }
-                    Rule::PROTOCOL_DEF => {
-                        let mut protocol_def = declarations.into_inner();
-                        let protocol_type = parse_protocol_type(protocol_def.next().unwrap());
-                        let name = protocol_def.next().unwrap().as_str();
-                        protocol.add_protocol(
-                            name,
-                            parse_fields(
-                                protocol_def.next().unwrap(),
-                                StructType::Protocol(protocol_type),
-                                &protocol,
-								pos,
-                            )?,
-                        ).map_err(|(msg, pos)| Error::new_from_span(
+                }
+                Rule::ENUM_DEF => {
+                    let mut enum_def = declaration.into_inner();
+                    let doc = parse_doc(&mut enum_def);
+                    let name = enum_def.next().unwrap().as_str();
+                    let mut enum_ = parse_enum(enum_def.next().unwrap(), pos)?;
+                    enum_.doc = doc;
+                    protocol.add_enum(name, enum_).map_err(|(msg, pos)| Error::new_from_span(
+							ErrorVariant::CustomError { message: msg },
+							pos,
+						))?;
+                }
+                Rule::TYPE_DEF => {
+                    let mut type_def = declaration.into_inner();
+                    let doc = parse_doc(&mut type_def);
+                    let name = type_def.next().unwrap().as_str();
+                    let alias_type = parse_type(type_def.next().unwrap(), protocol)?;
+                    let mut alias = Alias::new(parse_suffixes(alias_type, type_def, protocol)?, pos);
+                    alias.doc = doc;
+                    protocol.add_alias(name, alias).map_err(|(msg, pos)| Error::new_from_span(
+							ErrorVariant::CustomError { message: msg },
+							pos,
+						))?;
+                }
+                Rule::PROTOCOL_DEF => {
+                    let mut protocol_def = declaration.into_inner();
+                    let doc = parse_doc(&mut protocol_def);
+                    let protocol_type = parse_protocol_type(protocol_def.next().unwrap());
+                    let name = protocol_def.next().unwrap().as_str();
+                    let mut fields = parse_fields(
+                        protocol_def.next().unwrap(),
+                        StructType::Protocol(protocol_type),
+                        pos,
+                        protocol,
+                    )?;
+                    fields.doc = doc;
+                    protocol.add_protocol(name, fields).map_err(|(msg, pos)| Error::new_from_span(
 							ErrorVariant::CustomError { message: msg },
 							pos,
 						))?;
-                    }
-                    _ => unreachable!(),
                 }
+                _ => unreachable!(),
             }
-            Ok(protocol)
+            Ok(())
         }
 
-        parse_protocol(protocol)
+        let dir = match path {
+            Some(path) => Path::new(path).parent().unwrap_or(Path::new("")).to_path_buf(),
+            None => PathBuf::new(),
+        };
+        sources.load_imports(file, &dir);
+        let mut imports = Imports::new(sources);
+        if let Some(canonical) = path.and_then(|path| Path::new(path).canonicalize().ok()) {
+            imports.stack.push(canonical.clone());
+            imports.done.insert(canonical);
+        }
+        let add_path = |e: Error<Rule>| match (e.path(), path) {
+            (None, Some(path)) => e.with_path(path),
+            _ => e,
+        };
+        let mut protocol = Protocol::default();
+        for declaration in program.clone().into_inner() {
+            match declaration.as_rule() {
+                Rule::PACKAGE_DEF => protocol.set_package(declaration.into_inner().next().unwrap().as_str()),
+                Rule::VERSION_DEF => protocol.set_version(parse_version(declaration).map_err(add_path)?),
+                _ => break,
+            }
+        }
+        collect_declarations(program, &mut protocol, &dir, &mut imports).map_err(add_path)?;
+        parse_constants(&imports.constants, &mut protocol, &imports, path)?;
+        for declaration in imports.declarations.clone() {
+            let span = declaration.as_span();
+            parse_declaration(declaration, &mut protocol).map_err(|e| imports.locate(e, span, path))?;
+        }
+        protocol.resolve().map_err(|(msg, pos)| imports.with_path(
+			Error::new_from_span(ErrorVariant::CustomError { message: msg }, pos),
+			pos,
+			path,
+		))?;
+        Ok(protocol)
     }
 }
@@ -3,141 +3,395 @@ use std::{collections::{HashMap, HashSet}, fmt::Debug};
 use pest::Span;
 
 use crate::{
+    constraint::{Constraint, ConstraintKind},
     struct_::{ProtocolType, StructType},
     types::Types,
-    Struct,
+    Alias, Constant, Enum, Field, Struct,
 };
 
-/// A struct that contains all the structs and protocols defined in the protocol file
+/// A struct that contains all the structs, enums, aliases, constants and protocols defined in the protocol file
+/// Declarations are added while parsing and `resolve` must be called once the whole file is parsed
+#[derive(Default)]
 pub struct Protocol<'a> {
+	enums_order: Vec<&'a str>,
+	enums: HashMap<&'a str, Enum<'a>>,
 	structs_order: Vec<&'a str>,
     structs: HashMap<&'a str, Struct<'a>>,
+	protocols_order: Vec<&'a str>,
     protocols: HashMap<&'a str, Struct<'a>>,
+	aliases_order: Vec<&'a str>,
+	aliases: HashMap<&'a str, Alias<'a>>,
+	constants_order: Vec<&'a str>,
+	constants: HashMap<&'a str, Constant<'a>>,
+	/// Prefix of the generated names
+	package: Option<String>,
+	/// Version of the protocols that don't have their own
+	version: Option<u32>,
+	/// Typst version targeted by the generated code, as its major and minor numbers
+	typst_version: Option<(u32, u32)>,
 }
 
-impl<'a> Default for Protocol<'a> {
-    fn default() -> Protocol<'a> {
-        Protocol {
-			structs_order: Vec::new(),
-            structs: HashMap::new(),
-            protocols: HashMap::new(),
-        }
-    }
+/// Replace the struct names that refer to an enum or an alias by the enum or alias type
+/// and check that the others exist, `aliases` holds the resolved type of each alias
+fn resolve_type(t: &mut Types, enums: &HashSet<&str>, structs: &HashSet<&str>, aliases: &HashMap<&str, Types>) -> Result<(), String> {
+	match t {
+		Types::Struct(name) => {
+			if enums.contains(name.as_str()) {
+				*t = Types::Enum(name.clone());
+			} else if let Some(alias) = aliases.get(name.as_str()) {
+				*t = Types::Alias(name.clone(), Box::new(alias.clone()));
+			} else if !structs.contains(name.as_str()) {
+				return Err(format!("Struct \"{}\" not found", name));
+			}
+		}
+		Types::Array(t) | Types::FixedArray(t, _) | Types::Optional(t) | Types::Map(t) => {
+			resolve_type(t, enums, structs, aliases)?;
+		}
+		_ => {}
+	}
+	Ok(())
+}
+
+/// Resolve the types and check the default values and constraints of the fields of a struct
+fn resolve_fields<'a>(
+	struct_: &mut Struct<'a>,
+	enums_defs: &HashMap<&'a str, Enum<'a>>,
+	enums: &HashSet<&str>,
+	structs: &HashSet<&str>,
+	aliases: &HashMap<&str, Types>,
+) -> Result<(), (String, Span<'a>)> {
+	let mut typst_names = HashSet::new();
+	for field in struct_.iter_mut() {
+		resolve_type(&mut field.type_, enums, structs, aliases).map_err(|msg| (msg, field.pos))?;
+		for attribute in &field.attributes {
+			attribute.check_type(&field.type_).map_err(|msg| (msg, attribute.pos))?;
+		}
+		if let Some(range) = field.attribute("range") {
+			let kind = ConstraintKind::Range(range.args[0].clone(), range.args[1].clone());
+			field.constraints.push(Constraint { kind, pos: range.pos });
+		}
+		for constraint in &mut field.constraints {
+			constraint.check_type(&field.type_).map_err(|msg| (msg, constraint.pos))?;
+		}
+		if !typst_names.insert(field.typst_name().to_string()) {
+			return Err((format!("Typst name \"{}\" already used by another field", field.typst_name()), field.pos));
+		}
+		if let Some(default) = field.default.take() {
+			let enum_ = match field.type_.resolved() {
+				Types::Enum(name) => enums_defs.get(name.as_str()),
+				_ => None,
+			};
+			let default = default
+				.resolve(&field.type_, enum_)
+				.map_err(|msg| (msg, field.default_pos.unwrap_or(field.pos)))?;
+			if let Some(constraint) = field.constraints.iter().find(|constraint| !constraint.allows(&default)) {
+				return Err((format!("The default value doesn't respect the constraint {:?}", constraint), field.default_pos.unwrap_or(field.pos)));
+			}
+			field.default = Some(default);
+		}
+	}
+	Ok(())
+}
+
+/// Check that the fields added with `@since` are at the end of a protocol, from the oldest to the newest,
+/// and that they can be filled when an older payload doesn't have them
+fn check_since_fields<'a>(struct_: &Struct<'a>) -> Result<(), (String, Span<'a>)> {
+	let mut last = None;
+	for field in struct_.iter() {
+		let (Some(attribute), Some(since)) = (field.attribute("since"), field.since()) else {
+			if last.is_some() {
+				return Err(("Fields without @since must be before the fields added with @since".to_string(), field.pos));
+			}
+			continue;
+		};
+		if !struct_.is_protocol() {
+			return Err(("@since can only be used on protocol fields".to_string(), attribute.pos));
+		}
+		if last.is_some_and(|last| since < last) {
+			return Err(("Fields added with @since must be sorted from the oldest to the newest version".to_string(), attribute.pos));
+		}
+		if let Some(version) = struct_.version.filter(|version| since > *version) {
+			return Err((format!("@since({}) is newer than the version {} of the protocol", since, version), attribute.pos));
+		}
+		if field.default.is_none() && !matches!(field.type_.resolved(), Types::Optional(_) | Types::Array(_) | Types::Map(_) | Types::Bytes) {
+			return Err((
+				"A field added with @since must have a default value or be an optional, a list, a map or bytes".to_string(),
+				field.pos,
+			));
+		}
+		last = Some(since);
+	}
+	Ok(())
 }
 
 impl<'a> Protocol<'a> {
 	/// Check for circular dependencies in the structs children types
+	/// A struct can only contain itself through an optional, an array or a map
 	fn check_circular_dependencies(&self, struct_: &Struct<'a>, parents: &HashSet<&str>) -> Result<(), (String, Span<'a>)> {
-		for (_, t, pos) in struct_.iter() {
-			match t {
-				Types::Struct(name) => {
-					if parents.contains(name.as_str()) {
-						return Err((
-							format!("Circular dependency detected: {} is its own parent", name),
-							pos.clone()
-						))
-					}
-					if let Some(s) = self.structs.get(name.as_str()) {
-						let mut parents = parents.clone();
-						parents.insert(name.as_str());
-						self.check_circular_dependencies(s, &parents)?;
-					}
+		for Field { type_: t, pos, .. } in struct_.iter() {
+			if let Some(name) = t.inline_struct_name() {
+				if parents.contains(name) {
+					return Err((
+						format!("Circular dependency detected: {} is its own parent, use an optional or an array to make it recursive", name),
+						*pos
+					))
+				}
+				if let Some(s) = self.structs.get(name) {
+					let mut parents = parents.clone();
+					parents.insert(name);
+					self.check_circular_dependencies(s, &parents)?;
 				}
-				_ => {}
 			}
 		}
 		Ok(())
 	}
-	
-	pub fn pre_add_struct(&mut self, name: &'a str, struct_type : StructType, pos: pest::Span<'a>) {
-		self.structs.insert(name, Struct::new(struct_type, pos));
-		self.structs_order.push(name);
+
+	/// Check if a type, constant or protocol with this name is already defined
+	/// Constants share the names of the types as they are macros in C
+	fn is_defined(&self, name: &str) -> bool {
+		self.has_enum(name) || self.has_struct(name) || self.has_protocol(name) || self.has_alias(name) || self.constants.contains_key(name)
+	}
+
+	/// Resolve the type named by an alias, after the aliases it uses
+	fn resolve_alias(
+		&self,
+		name: &'a str,
+		enums: &HashSet<&str>,
+		structs: &HashSet<&str>,
+		resolved: &mut HashMap<&'a str, Types>,
+		stack: &mut Vec<&'a str>,
+	) -> Result<(), (String, Span<'a>)> {
+		if resolved.contains_key(name) {
+			return Ok(());
+		}
+		let alias = &self.aliases[name];
+		if stack.contains(&name) {
+			let cycle = stack[stack.iter().position(|n| *n == name).unwrap()..].join(" -> ");
+			return Err((format!("Circular type alias detected: {} -> {}", cycle, name), alias.pos));
+		}
+		stack.push(name);
+		// types have a single named type at their end
+		if let Some((used, _)) = alias.type_.struct_name().and_then(|used| self.aliases.get_key_value(used)) {
+			self.resolve_alias(used, enums, structs, resolved, stack)?;
+		}
+		let mut t = alias.type_.clone();
+		resolve_type(&mut t, enums, structs, resolved).map_err(|msg| (msg, alias.pos))?;
+		stack.pop();
+		resolved.insert(name, t);
+		Ok(())
+	}
+
+	/// Add a new enum to the program, its name must not be used by another enum or struct
+	pub fn add_enum(&mut self, name: &'a str, enum_: Enum<'a>) -> Result<(), (String, Span<'a>)> {
+		if self.is_defined(name) {
+			return Err((format!("Type \"{}\" already defined", name), enum_.get_pos()));
+		}
+		self.enums.insert(name, enum_);
+		self.enums_order.push(name);
+		Ok(())
 	}
 
+	/// Add a new struct or union to the program, the types of its fields are resolved by `resolve`
     pub fn add_struct(&mut self, name: &'a str, struct_: Struct<'a>) -> Result<(), (String, Span<'a>)> {
-		let mut set = HashSet::new();
-		set.insert(name);
-		self.check_circular_dependencies(&struct_, &set)?;
-        if self.structs.insert(name, struct_).is_none() {
-			self.structs_order.push(name);
+		if self.is_defined(name) {
+			return Err((format!("Type \"{}\" already defined", name), struct_.get_pos()));
 		}
+        self.structs.insert(name, struct_);
+		self.structs_order.push(name);
 		Ok(())
     }
 
+	/// Add a new type alias to the program, the type it names is resolved by `resolve`
+	pub fn add_alias(&mut self, name: &'a str, alias: Alias<'a>) -> Result<(), (String, Span<'a>)> {
+		if self.is_defined(name) {
+			return Err((format!("Type \"{}\" already defined", name), alias.pos));
+		}
+		self.aliases.insert(name, alias);
+		self.aliases_order.push(name);
+		Ok(())
+	}
+
+	/// Add a new constant to the program, its value must already match its type
+	pub fn add_constant(&mut self, name: &'a str, constant: Constant<'a>) -> Result<(), (String, Span<'a>)> {
+		if self.is_defined(name) {
+			return Err((format!("Constant \"{}\" already defined", name), constant.pos));
+		}
+		self.constants.insert(name, constant);
+		self.constants_order.push(name);
+		Ok(())
+	}
 
 	/// Add a new protocol to the program
     pub fn add_protocol(&mut self, name: &'a str, protocol: Struct<'a>) -> Result<(), (String, Span<'a>)> {
-		for (_, t, pos) in protocol.iter() {
-			match t {
-				Types::Struct(name) => {
-					self.set_struct_encoding_type(name, pos, &protocol)?;
-				}
-				Types::Array(t) => {
-					if let Types::Struct(name) = t.as_ref() {
-						self.set_struct_encoding_type(name, pos, &protocol)?;
+		if self.is_defined(name) {
+			return Err((format!("Protocol \"{}\" already defined", name), protocol.get_pos()));
+		}
+        self.protocols.insert(name, protocol);
+		self.protocols_order.push(name);
+		Ok(())
+    }
+
+	/// Resolve the types and default values of the fields once every declaration is known,
+	/// so declarations can be used before being defined.
+	/// It also checks for circular dependencies, sorts the structs so each one comes after
+	/// the structs it uses and sets the encoding type of the structs used by the protocols
+	pub fn resolve(&mut self) -> Result<(), (String, Span<'a>)> {
+		// a field using the name of a built-in type is parsed as the built-in type, so it can't be declared
+		let declarations = self.enums_order.iter().map(|name| (*name, self.enums[name].get_pos()))
+			.chain(self.structs_order.iter().map(|name| (*name, self.structs[name].get_pos())))
+			.chain(self.protocols_order.iter().map(|name| (*name, self.protocols[name].get_pos())))
+			.chain(self.aliases_order.iter().map(|name| (*name, self.aliases[name].pos)));
+		for (name, pos) in declarations {
+			if Types::is_builtin(name) {
+				return Err((format!("\"{}\" is a built-in type and can't be declared", name), pos));
+			}
+		}
+		let enums = self.enums.keys().copied().collect::<HashSet<_>>();
+		let structs = self.structs.keys().copied().collect::<HashSet<_>>();
+		let mut aliases = HashMap::new();
+		for name in &self.aliases_order {
+			self.resolve_alias(name, &enums, &structs, &mut aliases, &mut Vec::new())?;
+		}
+		for (name, t) in &aliases {
+			self.aliases.get_mut(name).unwrap().type_ = t.clone();
+		}
+		for name in &self.structs_order {
+			let struct_ = self.structs.get_mut(name).unwrap();
+			resolve_fields(struct_, &self.enums, &enums, &structs, &aliases)?;
+			check_since_fields(struct_)?;
+		}
+		for name in &self.protocols_order {
+			let protocol = self.protocols.get_mut(name).unwrap();
+			resolve_fields(protocol, &self.enums, &enums, &structs, &aliases)?;
+			protocol.version = protocol.version.or(self.version);
+			check_since_fields(protocol)?;
+		}
+
+		for name in &self.structs_order {
+			let mut parents = HashSet::new();
+			parents.insert(*name);
+			self.check_circular_dependencies(&self.structs[name], &parents)?;
+		}
+		self.sort_structs();
+
+		for name in self.protocols_order.clone() {
+			let protocol = self.protocols.remove(name).unwrap();
+			let result = protocol.iter().try_for_each(|Field { type_: t, pos, .. }| match t.struct_name() {
+				Some(name) => self.set_struct_encoding_type(name, pos, &protocol),
+				None => Ok(()),
+			});
+			self.protocols.insert(name, protocol);
+			result?;
+		}
+		Ok(())
+	}
+
+	/// Sort the structs so each struct comes after the structs stored inline in its fields
+	/// or in the aliases they use, keeping the order of the file when possible
+	fn sort_structs(&mut self) {
+		fn visit<'a>(name: &'a str, structs: &HashMap<&'a str, Struct<'a>>, visited: &mut HashSet<&'a str>, order: &mut Vec<&'a str>) {
+			if !visited.insert(name) {
+				return;
+			}
+			for field in structs[name].iter() {
+				for child in field.type_.defined_before() {
+					if let Some((child, _)) = structs.get_key_value(child) {
+						visit(child, structs, visited, order);
 					}
 				}
-				Types::Optional(t) => {
-					if let Types::Struct(name) = t.as_ref() {
-						self.set_struct_encoding_type(name, pos, &protocol)?;
+			}
+			order.push(name);
+		}
+		let mut visited = HashSet::new();
+		let mut order = Vec::new();
+		for name in &self.structs_order {
+			visit(name, &self.structs, &mut visited, &mut order);
+		}
+		self.structs_order = order;
+	}
+
+	/// Group the structs that use each other, directly or through other structs
+	/// Groups are sorted so each group comes after the groups it uses
+	pub fn struct_groups(&self) -> Vec<Vec<&'a str>> {
+		// Tarjan's strongly connected components algorithm
+		struct State<'a> {
+			index: HashMap<&'a str, usize>,
+			low: HashMap<&'a str, usize>,
+			stack: Vec<&'a str>,
+			on_stack: HashSet<&'a str>,
+			groups: Vec<Vec<&'a str>>,
+		}
+		fn visit<'a>(name: &'a str, structs: &HashMap<&'a str, Struct<'a>>, state: &mut State<'a>) {
+			let index = state.index.len();
+			state.index.insert(name, index);
+			state.low.insert(name, index);
+			state.stack.push(name);
+			state.on_stack.insert(name);
+			for field in structs[name].iter() {
+				let Some((child, _)) = field.type_.struct_name().and_then(|child| structs.get_key_value(child)) else {
+					continue;
+				};
+				if !state.index.contains_key(child) {
+					visit(child, structs, state);
+					let low = state.low[name].min(state.low[child]);
+					state.low.insert(name, low);
+				} else if state.on_stack.contains(child) {
+					let low = state.low[name].min(state.index[child]);
+					state.low.insert(name, low);
+				}
+			}
+			if state.low[name] == state.index[name] {
+				let mut group = Vec::new();
+				while let Some(member) = state.stack.pop() {
+					state.on_stack.remove(member);
+					group.push(member);
+					if member == name {
+						break;
 					}
 				}
-				_ => {}
+				group.reverse();
+				state.groups.push(group);
 			}
 		}
-        self.protocols.insert(name, protocol);
-		Ok(())
-    }
+		let mut state = State {
+			index: HashMap::new(),
+			low: HashMap::new(),
+			stack: Vec::new(),
+			on_stack: HashSet::new(),
+			groups: Vec::new(),
+		};
+		for name in &self.structs_order {
+			if !state.index.contains_key(name) {
+				visit(name, &self.structs, &mut state);
+			}
+		}
+		state.groups
+	}
 
 	fn update_children_encoding_type(&mut self, name: &str) {
 		let structs = self.structs.get(name).unwrap();
 		let encoder = structs.encoder;
 		let decoder = structs.decoder;
-		let structs = structs.iter().filter(|(_, t, _)| t.is_struct()).map(|(_, t, _)| t.clone()).collect::<Vec<_>>();
-		for t in structs {
-			match t {
-				Types::Struct(name) => {
-					let s = self.structs.get_mut(name.as_str()).unwrap();
-					if s.encoder != encoder || s.decoder != decoder {
-						s.encoder |= encoder;
-						s.decoder |= decoder;
-						self.update_children_encoding_type(name.as_str());
-					}
-				}
-				Types::Array(t) => {
-					if let Types::Struct(name) = t.as_ref() {
-						let s = self.structs.get_mut(name.as_str()).unwrap();
-						if s.encoder != encoder || s.decoder != decoder {
-							s.encoder |= encoder;
-							s.decoder |= decoder;
-							self.update_children_encoding_type(name.as_str());
-						}
-					}
-				}
-				Types::Optional(t) => {
-					if let Types::Struct(name) = t.as_ref() {
-						let s = self.structs.get_mut(name.as_str()).unwrap();
-						if s.encoder != encoder || s.decoder != decoder {
-							s.encoder |= encoder;
-							s.decoder |= decoder;
-							self.update_children_encoding_type(name.as_str());
-						}
-					}
-				}
-				_ => {}
+		let children = structs.iter().filter_map(|field| field.type_.struct_name()).map(|name| name.to_string()).collect::<Vec<_>>();
+		for name in children {
+			let s = self.structs.get_mut(name.as_str()).unwrap();
+			if s.encoder != encoder || s.decoder != decoder {
+				s.encoder |= encoder;
+				s.decoder |= decoder;
+				self.update_children_encoding_type(name.as_str());
 			}
 		}
 	}
 
 	/// Check the protocol type and set the encoding type of the struct accordingly
-    fn set_struct_encoding_type(&mut self, name: &String, pos: &Span<'a>, parent_protocol: &Struct<'a>) -> Result<(), (String, Span<'a>)> {
-        if !self.structs.contains_key(name.as_str()) {
+    fn set_struct_encoding_type(&mut self, name: &str, pos: &Span<'a>, parent_protocol: &Struct<'a>) -> Result<(), (String, Span<'a>)> {
+        if !self.structs.contains_key(name) {
 			return Err((format!("Struct \"{}\" does not exist", name),
-				pos.clone(),
+				*pos,
 			));
         }
 		if let StructType::Protocol(parent_type) = parent_protocol.get_type() {
-			let s = self.structs.get_mut(name.as_str()).unwrap();
+			let s = self.structs.get_mut(name).unwrap();
 			match parent_type {
 				ProtocolType::C => {
 					s.decoder = true;
@@ -157,6 +411,30 @@ impl<'a> Protocol<'a> {
 		Ok(())
     }
 
+	pub fn set_package(&mut self, package: &str) {
+		self.package = Some(package.to_string());
+	}
+
+	pub fn package(&self) -> Option<&str> {
+		self.package.as_deref()
+	}
+
+	pub fn set_version(&mut self, version: u32) {
+		self.version = Some(version);
+	}
+
+	pub fn version(&self) -> Option<u32> {
+		self.version
+	}
+
+	pub fn set_typst_version(&mut self, version: (u32, u32)) {
+		self.typst_version = Some(version);
+	}
+
+	pub fn typst_version(&self) -> Option<(u32, u32)> {
+		self.typst_version
+	}
+
     pub fn has_protocol(&self, name: &str) -> bool {
         self.protocols.contains_key(name)
     }
@@ -165,6 +443,46 @@ impl<'a> Protocol<'a> {
         self.structs.contains_key(name)
     }
 
+	pub fn has_enum(&self, name: &str) -> bool {
+		self.enums.contains_key(name)
+	}
+
+	pub fn has_alias(&self, name: &str) -> bool {
+		self.aliases.contains_key(name)
+	}
+
+	pub fn ordered_aliases(&self) -> impl Iterator<Item = (&'a str, &Alias<'a>)> {
+		self.aliases_order.iter().map(|name| (*name, self.aliases.get(name).unwrap()))
+	}
+
+	pub fn get_constant(&self, name: &str) -> Option<&Constant<'a>> {
+		self.constants.get(name)
+	}
+
+	pub fn ordered_constants(&self) -> impl Iterator<Item = (&'a str, &Constant<'a>)> {
+		self.constants_order.iter().map(|name| (*name, self.constants.get(name).unwrap()))
+	}
+
+	pub fn get_alias(&self, name: &str) -> &Alias<'a> {
+		&self.aliases[name]
+	}
+
+	pub fn get_struct(&self, name: &str) -> Option<&Struct<'a>> {
+		self.structs.get(name)
+	}
+
+	pub fn get_protocol(&self, name: &str) -> Option<&Struct<'a>> {
+		self.protocols.get(name)
+	}
+
+	pub fn get_enum(&self, name: &str) -> Option<&Enum<'a>> {
+		self.enums.get(name)
+	}
+
+	pub fn ordered_enums(&self) -> impl Iterator<Item = (&'a str, &Enum<'a>)> {
+		self.enums_order.iter().map(|name| (*name, self.enums.get(name).unwrap()))
+	}
+
     pub fn structs(&self) -> std::collections::hash_map::Iter<'_, &str, Struct<'a>> {
         self.structs.iter()
     }
@@ -173,14 +491,23 @@ impl<'a> Protocol<'a> {
 		self.structs_order.iter().map(|name| (*name, self.structs.get(name).unwrap()))
 	}
 
-    pub fn protocols(&self) -> std::collections::hash_map::Iter<'_, &str, Struct<'a>> {
-        self.protocols.iter()
+    pub fn protocols(&self) -> impl Iterator<Item = (&'a str, &Struct<'a>)> {
+		self.protocols_order.iter().map(|name| (*name, self.protocols.get(name).unwrap()))
     }
 }
 
 impl<'a> Debug for Protocol<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Protocol {{")?;
+        for (name, enum_) in &self.enums {
+            write!(f, "\n{}: {:?}", name, enum_)?;
+        }
+        for (name, alias) in &self.aliases {
+            write!(f, "\n{}: {:?}", name, alias)?;
+        }
+        for (name, constant) in &self.constants {
+            write!(f, "\n{}: {:?}", name, constant)?;
+        }
         for (name, struct_) in &self.structs {
             write!(f, "\n{}: {:?} ({},{})", name, struct_, struct_.encoder, struct_.decoder)?;
         }
@@ -1,9 +1,9 @@
 use std::{collections::HashSet, fmt::Debug};
 
-use crate::types::Types;
+use crate::Field;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 /// Each protocol can be of type C, Typst or Bidirectional
 /// C: He protocol is encoded in Typst and decoded in C
 /// Typst: The protocol is encoded in C and decoded in Typst
@@ -14,12 +14,14 @@ pub enum ProtocolType {
 	Bidirectional,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 /// Struct and protocols are represented the same way so this enum is used to differentiate between them
 /// Struct: A struct used in the protocol, it's the same as a struct in C
+/// Union: A tagged union, only one of its fields is set and it's preceded by a discriminant byte
 /// Protocol: A protocol definition
 pub enum StructType {
     Struct,
+    Union,
     Protocol(ProtocolType),
 }
 
@@ -29,9 +31,13 @@ pub struct Struct<'a> {
 	pos: pest::Span<'a>,
     pub encoder: bool,
     pub decoder: bool,
+	/// Doc comment written before the declaration
+	pub doc: Option<String>,
+	/// Version of a protocol, sent before its fields
+	pub version: Option<u32>,
 	fields_names: HashSet<&'a str>,
-	// fields are stored in a vector of tuples (name, type) because the order matters
-    fields: Vec<(&'a str, Types, pest::Span<'a>)>,
+	// fields are stored in a vector because the order matters
+    fields: Vec<Field<'a>>,
 }
 
 impl<'a> Struct<'a> {
@@ -41,14 +47,16 @@ impl<'a> Struct<'a> {
 			pos,
             encoder: false,
             decoder: false,
+			doc: None,
+			version: None,
 			fields_names: HashSet::new(),
             fields: Vec::new()
         }
     }
 
-    pub fn add_field(&mut self, name: &'a str, field_type: Types, pos: pest::Span<'a>) {
-        self.fields.push((name, field_type, pos));
-		self.fields_names.insert(name);
+    pub fn add_field(&mut self, field: Field<'a>) {
+		self.fields_names.insert(field.name);
+        self.fields.push(field);
     }
 
 	pub fn has_field(&self, name: &str) -> bool {
@@ -59,17 +67,32 @@ impl<'a> Struct<'a> {
         &self.type_
     }
 
-    pub fn iter<'b>(&'b self) -> std::slice::Iter<'b, (&'a str, Types, pest::Span<'a>)> {
+	pub fn is_protocol(&self) -> bool {
+		matches!(self.type_, StructType::Protocol(_))
+	}
+
+    pub fn iter<'b>(&'b self) -> std::slice::Iter<'b, Field<'a>> {
         self.fields.iter()
     }
 
-	pub fn fields(&self) -> &Vec<(&'a str, Types, pest::Span<'a>)> {
+    pub fn iter_mut<'b>(&'b mut self) -> std::slice::IterMut<'b, Field<'a>> {
+        self.fields.iter_mut()
+    }
+
+	pub fn fields(&self) -> &Vec<Field<'a>> {
 		&self.fields
 	}
 
 	pub fn get_pos(&self) -> pest::Span<'a> {
 		self.pos
 	}
+
+	/// Oldest version accepted by the decoder of a versioned protocol,
+	/// payloads of the versions before a field added with `@since` don't have it
+	pub fn oldest_version(&self) -> Option<u32> {
+		let version = self.version?;
+		Some(self.iter().filter_map(|field| field.since()).map(|since| since - 1).fold(version, u32::min))
+	}
 }
 
 impl<'a> Debug for Struct<'a> {
@@ -78,6 +101,9 @@ impl<'a> Debug for Struct<'a> {
             StructType::Struct => {
                 write!(f, "Struct {{")?;
             }
+            StructType::Union => {
+                write!(f, "Union {{")?;
+            }
             StructType::Protocol(protocol_type) => {
                 write!(f, "Protocol ")?;
                 match protocol_type {
@@ -93,8 +119,15 @@ impl<'a> Debug for Struct<'a> {
                 }
             }
         }
-        for (name, field, _) in &self.fields {
-            write!(f, "\n\t{}: {:?}", name, field)?;
+        for field in &self.fields {
+            write!(f, "\n\t")?;
+            for attribute in &field.attributes {
+                write!(f, "{:?} ", attribute)?;
+            }
+            write!(f, "{}: {:?}", field.name, field.type_)?;
+            if let Some(default) = &field.default {
+                write!(f, " = {:?}", default)?;
+            }
         }
         write!(f, "\n}}")
     }
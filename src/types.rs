@@ -1,17 +1,106 @@
 use std::fmt::Debug;
 
+/// Color space of a `color` field, chosen with `color<cmyk>` for example
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+	/// RGBA color, each component is encoded on a byte
+	Rgb,
+	Luma,
+	Cmyk,
+	Oklab,
+	Oklch,
+	LinearRgb,
+	Hsl,
+	Hsv,
+}
+
+impl ColorSpace {
+	pub const ALL: [ColorSpace; 8] = [
+		ColorSpace::Rgb,
+		ColorSpace::Luma,
+		ColorSpace::Cmyk,
+		ColorSpace::Oklab,
+		ColorSpace::Oklch,
+		ColorSpace::LinearRgb,
+		ColorSpace::Hsl,
+		ColorSpace::Hsv,
+	];
+
+	pub fn parse(name: &str) -> Option<ColorSpace> {
+		ColorSpace::ALL.into_iter().find(|space| space.name() == name)
+	}
+
+	/// Name of the color space in the protocol file and in Typst
+	pub fn name(&self) -> &'static str {
+		match self {
+			ColorSpace::Rgb => "rgb",
+			ColorSpace::Luma => "luma",
+			ColorSpace::Cmyk => "cmyk",
+			ColorSpace::Oklab => "oklab",
+			ColorSpace::Oklch => "oklch",
+			ColorSpace::LinearRgb => "linear-rgb",
+			ColorSpace::Hsl => "hsl",
+			ColorSpace::Hsv => "hsv",
+		}
+	}
+
+	/// Names of the components of a color in this space, in the order of the Typst `components` method
+	pub fn components(&self) -> &'static [&'static str] {
+		match self {
+			ColorSpace::Rgb | ColorSpace::LinearRgb => &["r", "g", "b", "a"],
+			ColorSpace::Luma => &["l", "a"],
+			ColorSpace::Cmyk => &["c", "m", "y", "k"],
+			ColorSpace::Oklab => &["l", "a", "b", "alpha"],
+			ColorSpace::Oklch => &["l", "c", "h", "alpha"],
+			ColorSpace::Hsl => &["h", "s", "l", "a"],
+			ColorSpace::Hsv => &["h", "s", "v", "a"],
+		}
+	}
+}
+
 /// Represents the different types that can be used in the protocol
 #[derive(Clone)]
 pub enum Types {
     Int,
+    /// Integer with an explicit size in bytes and signedness
+    Integer { bytes: usize, signed: bool },
     Float,
+    Double,
 	Point,
+	/// Typst length with an absolute part in points and a part in em
+	Length,
+	/// Typst angle, in degrees in C
+	Angle,
+	/// Typst ratio, 1 is 100% in C
+	Ratio,
+	/// Typst relative length, a length plus a ratio
+	Relative,
+	/// Typst fraction, in fr in C
+	Fraction,
+	Color(ColorSpace),
+	/// Typst datetime with a date and a time
+	Datetime,
+	/// Typst datetime with only a date
+	Date,
+	/// Typst duration, a number of seconds in C
+	Duration,
+	/// Styled text with a color and a size, rendered as content in Typst
+	TextRun,
     Bool,
     Char,
     String,
+    /// Raw binary data, encoded as a length followed by the data
+    Bytes,
     Array(Box<Types>),
+    /// Array with a length known from the schema, encoded without a length prefix
+    FixedArray(Box<Types>, usize),
     Optional(Box<Types>),
+    /// Dictionary with string keys, encoded as a length followed by the key/value pairs
+    Map(Box<Types>),
     Struct(String),
+    Enum(String),
+    /// Name given to another type with `type Name = type;`, encoded like the named type
+    Alias(String, Box<Types>),
 }
 
 impl Types {
@@ -19,52 +108,148 @@ impl Types {
         match type_str {
             "int" => Types::Int,
             "float" => Types::Float,
+            "double" => Types::Double,
             "bool" => Types::Bool,
             "char" => Types::Char,
             "string" => Types::String,
+            "bytes" => Types::Bytes,
 			"point" => Types::Point,
+			"length" => Types::Length,
+			"angle" => Types::Angle,
+			"ratio" => Types::Ratio,
+			"relative" => Types::Relative,
+			"fraction" => Types::Fraction,
+			"datetime" => Types::Datetime,
+			"date" => Types::Date,
+			"duration" => Types::Duration,
+			"text_run" => Types::TextRun,
+            "i8" => Types::Integer { bytes: 1, signed: true },
+            "u8" => Types::Integer { bytes: 1, signed: false },
+            "i16" => Types::Integer { bytes: 2, signed: true },
+            "u16" => Types::Integer { bytes: 2, signed: false },
+            "i32" => Types::Integer { bytes: 4, signed: true },
+            "u32" => Types::Integer { bytes: 4, signed: false },
+            "i64" => Types::Integer { bytes: 8, signed: true },
+            "u64" => Types::Integer { bytes: 8, signed: false },
             _ => Types::Struct(type_str.to_string()),
         }
     }
 
+	/// Check if a name is the name of a built-in type, fields using it are always read as the built-in type
+	pub fn is_builtin(name: &str) -> bool {
+		name == "color" || !matches!(Types::parse(name), Types::Struct(_))
+	}
+
+	/// Type named by the type, looking through aliases
+	pub fn resolved(&self) -> &Types {
+		match self {
+			Types::Alias(_, t) => t.resolved(),
+			_ => self,
+		}
+	}
+
 	/// Check if the type is a struct or an array of structs
 	pub fn is_struct(&self) -> bool {
 		match self {
 			Types::Struct(_) => true,
-			Types::Array(t) => t.is_struct(),
+			Types::Array(t) | Types::FixedArray(t, _) | Types::Map(t) | Types::Alias(_, t) => t.is_struct(),
             Types::Optional(t) => t.is_struct(),
 			_ => false,
 		}
 	}
 
+	/// Name of the struct contained in the type, looking through arrays and optionals
+	pub fn struct_name(&self) -> Option<&str> {
+		match self {
+			Types::Struct(name) => Some(name),
+			Types::Array(t) | Types::FixedArray(t, _) | Types::Optional(t) | Types::Map(t) | Types::Alias(_, t) => t.struct_name(),
+			_ => None,
+		}
+	}
+
+	/// Name of the struct stored inline in the type
+	/// Structs in an array, an optional or a map are stored behind a pointer so they are not inline
+	pub fn inline_struct_name(&self) -> Option<&str> {
+		match self {
+			Types::Struct(name) => Some(name),
+			Types::FixedArray(t, _) | Types::Alias(_, t) => t.inline_struct_name(),
+			_ => None,
+		}
+	}
+
+	/// Names of the structs that must be defined before a value of this type,
+	/// the structs stored inline in it or in one of the aliases it uses
+	pub fn defined_before(&self) -> Vec<&str> {
+		let mut names = Vec::new();
+		let mut t = self;
+		names.extend(t.inline_struct_name());
+		while let Types::Array(inner) | Types::FixedArray(inner, _) | Types::Optional(inner) | Types::Map(inner) | Types::Alias(_, inner) = t {
+			if let Types::Alias(_, target) = t {
+				names.extend(target.inline_struct_name());
+			}
+			t = inner;
+		}
+		names
+	}
+
     pub fn to_c(&self, in_struct: bool) -> String {
         match self {
             Types::Int => "int".to_string(),
-            Types::Float | Types::Point => "float".to_string(),
+            Types::Integer { bytes, signed } => format!("{}int{}_t", if *signed { "" } else { "u" }, bytes * 8),
+            Types::Float | Types::Point | Types::Angle | Types::Ratio | Types::Fraction => "float".to_string(),
+            Types::Double => "double".to_string(),
+            Types::Length => "length".to_string(),
+            Types::Relative => "relative".to_string(),
+            Types::Color(ColorSpace::Rgb) => "color".to_string(),
+            Types::Color(space) => format!("color_{}", space.name().replace('-', "_")),
+            Types::Datetime => "datetime".to_string(),
+            Types::Date => "date".to_string(),
+            Types::Duration => "int64_t".to_string(),
+            Types::TextRun => "text_run".to_string(),
             Types::Bool => "bool".to_string(),
             Types::Char => "char".to_string(),
             Types::String => "char*".to_string(),
+            Types::Bytes => "uint8_t *".to_string(),
             Types::Array(t) => format!("{} *", t.to_c(in_struct)),
+            Types::FixedArray(t, size) => format!("{}[{}]", t.to_c(in_struct), size),
             Types::Struct(name) => if in_struct {
 				format!("struct {}_t", name)
 			} else {
 				name.to_string()
 			}
+            Types::Enum(name) | Types::Alias(name, _) => name.to_string(),
             Types::Optional(t) => format!("{} *", t.to_c(in_struct)),
+            Types::Map(t) => format!("{}_map", t.to_c(in_struct)),
         }
     }
 
 	pub fn to_typst(&self) -> String {
 		match self {
 			Types::Int => "int".to_string(),
+			Types::Integer { .. } => format!("{:?}", self),
 			Types::Float => "float".to_string(),
+			Types::Double => "double".to_string(),
 			Types::Point => "point".to_string(),
+			Types::Length => "length".to_string(),
+			Types::Angle => "angle".to_string(),
+			Types::Ratio => "ratio".to_string(),
+			Types::Relative => "relative".to_string(),
+			Types::Fraction => "fraction".to_string(),
+			Types::Color(ColorSpace::Rgb) => "color".to_string(),
+			Types::Color(space) => format!("color-{}", space.name()),
+			Types::Datetime => "datetime".to_string(),
+			Types::Date => "date".to_string(),
+			Types::Duration => "duration".to_string(),
+			Types::TextRun => "text-run".to_string(),
 			Types::Bool => "bool".to_string(),
 			Types::Char => "char".to_string(),
 			Types::String => "string".to_string(),
+			Types::Bytes => "bytes".to_string(),
 			Types::Array(t) => format!("{}[]", t.to_typst()),
-			Types::Struct(name) => name.to_string(),
-            Types::Optional(t) => format!("{}", t.to_typst()),
+			Types::FixedArray(t, size) => format!("{}[{}]", t.to_typst(), size),
+			Types::Struct(name) | Types::Enum(name) | Types::Alias(name, _) => name.to_string(),
+            Types::Optional(t) => t.to_typst(),
+            Types::Map(t) => format!("map<string, {}>", t.to_typst()),
 		}
 	}
 }
@@ -73,14 +258,30 @@ impl Debug for Types {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Types::Int => write!(f, "int"),
+            Types::Integer { bytes, signed } => write!(f, "{}{}", if *signed { "i" } else { "u" }, bytes * 8),
             Types::Float => write!(f, "float"),
+            Types::Double => write!(f, "double"),
 			Types::Point => write!(f, "point"),
+			Types::Length => write!(f, "length"),
+			Types::Angle => write!(f, "angle"),
+			Types::Ratio => write!(f, "ratio"),
+			Types::Relative => write!(f, "relative"),
+			Types::Fraction => write!(f, "fraction"),
+			Types::Color(ColorSpace::Rgb) => write!(f, "color"),
+			Types::Color(space) => write!(f, "color<{}>", space.name()),
+			Types::Datetime => write!(f, "datetime"),
+			Types::Date => write!(f, "date"),
+			Types::Duration => write!(f, "duration"),
+			Types::TextRun => write!(f, "text_run"),
             Types::Bool => write!(f, "bool"),
             Types::Char => write!(f, "char"),
             Types::String => write!(f, "string"),
+            Types::Bytes => write!(f, "bytes"),
             Types::Array(t) => write!(f, "{:?}[]", t),
-            Types::Struct(name) => write!(f, "{}", name),
+            Types::FixedArray(t, size) => write!(f, "{:?}[{}]", t, size),
+            Types::Struct(name) | Types::Enum(name) | Types::Alias(name, _) => write!(f, "{}", name),
             Types::Optional(t) => write!(f, "{:?}?", t),
+            Types::Map(t) => write!(f, "map<string, {:?}>", t),
         }
     }
 }